@@ -1 +1,4 @@
-go build -o api.exe -ldflags "-s -w" src/main.go
\ No newline at end of file
+$version = git describe --tags --always
+$commit = git rev-parse --short HEAD
+$built = (Get-Date).ToUniversalTime().ToString("yyyy-MM-ddTHH:mm:ssZ")
+go build -o api.exe -ldflags "-s -w -X main.version=$version -X main.commit=$commit -X main.buildTime=$built" ./src
@@ -1,2 +1,5 @@
 #!/bin/bash
-go build -o api -ldflags "-s -w" src/main.go
\ No newline at end of file
+VERSION=$(git describe --tags --always 2>/dev/null || echo dev)
+COMMIT=$(git rev-parse --short HEAD 2>/dev/null || echo unknown)
+BUILT=$(date -u +%Y-%m-%dT%H:%M:%SZ)
+go build -o api -ldflags "-s -w -X main.version=$VERSION -X main.commit=$COMMIT -X main.buildTime=$BUILT" ./src
@@ -1,16 +1,48 @@
 package main
 
 import (
+	"bytes"
+	"compress/gzip"
+	"container/list"
+	"context"
+	crand "crypto/rand"
+	"crypto/subtle"
+	_ "embed"
+	"encoding/base64"
+	"encoding/hex"
 	"encoding/json"
+	"errors"
 	"fmt"
+	"hash/fnv"
+	"html/template"
+	"image"
+	_ "image/gif"
+	"image/jpeg"
+	"image/png"
+	"io"
+	"io/fs"
+	"log/slog"
+	"maps"
+	"math"
+	"math/bits"
 	"math/rand"
+	"mime"
+	"net"
 	"net/http"
 	"os"
+	"os/signal"
+	"path"
 	"path/filepath"
 	"regexp"
 	"runtime"
+	"sort"
+	"strconv"
+	"strings"
 	"sync"
+	"sync/atomic"
+	"syscall"
 	"time"
+	"unicode/utf8"
 
 	"github.com/fsnotify/fsnotify"
 	"github.com/gin-gonic/gin"
@@ -18,222 +50,3295 @@ import (
 )
 
 const (
-	defaultGaryImg   = "Gary76.jpg"
-	defaultGooberImg = "goober8.jpg"
+	defaultGaryImg        = "Gary76.jpg"
+	defaultGooberImg      = "goober8.jpg"
+	defaultRequestTimeout = 10 * time.Second
+	defaultHeaderTimeout  = 5 * time.Second
+	defaultIdleTimeout    = 60 * time.Second
+	shutdownTimeout       = 5 * time.Second
+	streamHeartbeat       = 30 * time.Second
+	scanProgressInterval  = time.Second
+	watchDebounce         = 250 * time.Millisecond
+	docsCacheControl      = "public, max-age=3600"
+	faviconCacheControl   = "public, max-age=604800"
+	defaultDocsCSP        = "default-src 'self'; script-src 'self' 'unsafe-inline' https://cdn.jsdelivr.net; " +
+		"style-src 'self' 'unsafe-inline' https://cdn.jsdelivr.net; font-src 'self' https://cdn.jsdelivr.net; " +
+		"img-src * data:; connect-src *"
+	latencyBucketCount    = 28
+	sizeBucketCount       = 15
+	requestIDKey          = "requestID"
+	maxRequestIDLength    = 128
+	maxImageBatch         = 50
+	fileRetryBackoff      = 10 * time.Millisecond
+	maxResizeDimension    = 2048
+	maxResizeSourcePixels = 25_000_000
+	maxResizedBytes       = 64 << 20
+	maxBase64ImageBytes   = 4 << 20
+	manifestTimeout       = 10 * time.Second
+	socketProbeTimeout    = time.Second
 )
 
+// imageMimeTypes are the image formats served, registered with the mime
+// package so every one gets the right Content-Type whatever the host knows.
+var imageMimeTypes = map[string]string{
+	".avif": "image/avif",
+	".bmp":  "image/bmp",
+	".gif":  "image/gif",
+	".jpeg": "image/jpeg",
+	".jpg":  "image/jpeg",
+	".png":  "image/png",
+	".svg":  "image/svg+xml",
+	".tif":  "image/tiff",
+	".tiff": "image/tiff",
+	".webp": "image/webp",
+}
+
+// imageExtensions has a /gary/image.{ext} route each, one for every format
+// in imageMimeTypes.
+var imageExtensions = func() []string {
+	exts := make([]string, 0, len(imageMimeTypes))
+	for ext := range imageMimeTypes {
+		exts = append(exts, strings.TrimPrefix(ext, "."))
+	}
+	sort.Strings(exts)
+	return exts
+}()
+
+type imageResource struct {
+	label        string
+	defaultImage string
+	dir          string
+	baseURL      string
+	images       []string
+	imageSet     map[string]struct{}
+	latest       string
+	sizes        map[string]imageSize
+	files        map[string]imageFileInfo
+	fromManifest bool
+	watcher      *fsnotify.Watcher
+	bagMu        sync.Mutex
+	bag          []int
+	streamMu     sync.Mutex
+	streams      map[chan string]struct{}
+	bytesServed  atomic.Uint64
+	generation   atomic.Uint64
+}
+
+// streamsClosing is closed when the server shuts down, ending every open
+// event stream so Shutdown doesn't wait on them.
+var streamsClosing = make(chan struct{})
+
+var (
+	garyResource   = &imageResource{label: "Gary"}
+	gooberResource = &imageResource{label: "Goober"}
+	imageCacheMu   sync.RWMutex
+	cacheReady     atomic.Bool
+	activeConfig   atomic.Pointer[Config]
+	activeHandler  atomic.Pointer[http.Handler]
+	docs           atomic.Pointer[docsPage]
+)
+
+type docsPage struct {
+	plain   []byte
+	gzipped []byte
+	modTime time.Time
+}
+
+var (
+	version   = "dev"
+	commit    = "unknown"
+	buildTime = "unknown"
+)
+
+var errNoLines = errors.New("no lines found")
+
+var errImageTooLarge = errors.New("image has too many pixels to resize")
+
+// requestIDPattern is what a client-supplied X-Request-ID must look like to
+// be echoed and logged; anything else is replaced with a generated ID so it
+// can't smuggle control characters or markup into logs and headers.
+var requestIDPattern = regexp.MustCompile(`^[A-Za-z0-9._-]{1,` + strconv.Itoa(maxRequestIDLength) + `}$`)
+
+var jsonpCallbackPattern = regexp.MustCompile(`^[A-Za-z_$][A-Za-z0-9_$]*(\.[A-Za-z_$][A-Za-z0-9_$]*)*$`)
+
+var (
+	healthBody   = []byte(`{"status":"ok"}`)
+	notReadyBody = []byte(`{"status":"starting"}`)
+	timeoutBody  = []byte(`{"error":{"code":"timeout","message":"request timed out"}}`)
+)
+
+type serverMetrics struct {
+	startTime         time.Time
+	requests          atomic.Uint64
+	totalLatencyUs    atomic.Uint64
+	activeConnections atomic.Int64
+	latencyBuckets    [latencyBucketCount]atomic.Uint64
+	routeRequests     sync.Map
+	statusClasses     [4]atomic.Uint64
+	intervalRequests  atomic.Uint64
+	intervalLatencyUs atomic.Uint64
+	bytesSent         atomic.Uint64
+	uncompressedBytes atomic.Uint64
+	compressedBytes   atomic.Uint64
+	sizeBuckets       [sizeBucketCount + 1]atomic.Uint64
+	addressClasses    [3]atomic.Uint64
+	timeouts          atomic.Uint64
+}
+
+var statusClassLabels = [4]string{"2xx", "3xx", "4xx", "5xx"}
+
+var addressClassLabels = [3]string{"loopback", "private", "public"}
+
+type labeledCount struct {
+	Label string
+	Count uint64
+}
+
+var metrics = &serverMetrics{startTime: time.Now()}
+
+// openAPISpec is the OpenAPI document served at /openapi.json.
+//
+//go:embed openapi.json
+var openAPISpec []byte
+
+var statsTemplate = template.Must(template.New("stats").Parse(`<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="UTF-8" />
+  <meta http-equiv="refresh" content="5" />
+  <title>Gary API Stats</title>
+  <style>
+    body { font-family: -apple-system, BlinkMacSystemFont, sans-serif; margin: 2rem; color: #334155; }
+    table { border-collapse: collapse; }
+    th, td { text-align: left; padding: 0.4rem 1rem; border-bottom: 1px solid #e2e8f0; }
+  </style>
+</head>
+<body>
+  <h1>Gary API Stats</h1>
+  <table>
+    <tr><th>Uptime</th><td>{{.Uptime}}</td></tr>
+    <tr><th>Total requests</th><td>{{.Requests}}</td></tr>
+    <tr><th>Requests/sec</th><td>{{printf "%.2f" .RPS}}</td></tr>
+    <tr><th>Avg latency</th><td>{{.AvgLatency}}</td></tr>
+    <tr><th>Latency p50 / p95 / p99</th><td>{{.P50}} / {{.P95}} / {{.P99}}</td></tr>
+    <tr><th>Active connections</th><td>{{.ActiveConnections}}</td></tr>
+    <tr><th>Bytes sent</th><td>{{.BytesSent}}</td></tr>
+    <tr><th>Compression ratio</th><td>{{printf "%.2f" .CompressionRatio}}</td></tr>
+    <tr><th>Gary files</th><td>{{.GaryCount}}</td></tr>
+    <tr><th>Goober files</th><td>{{.GooberCount}}</td></tr>
+    <tr><th>Quotes</th><td>{{.QuoteCount}}</td></tr>
+    <tr><th>Jokes</th><td>{{.JokeCount}}</td></tr>
+  </table>
+  <h2>Requests by route</h2>
+  <table>
+    {{range .Routes}}<tr><th>{{.Label}}</th><td>{{.Count}}</td></tr>
+    {{end}}
+  </table>
+  <h2>Responses by status</h2>
+  <table>
+    {{range .Statuses}}<tr><th>{{.Label}}</th><td>{{.Count}}</td></tr>
+    {{end}}
+  </table>
+</body>
+</html>
+`))
+
+// requestTimeout gives each request a deadline for starting its response.
+// A handler that hasn't written anything when it passes, even one stuck in
+// a read from a hung disk, is answered with a 503 right then, inside gin, so
+// it is logged and counted like any other response. A response that has
+// started is left to finish however long the client takes. Event streams
+// never finish on their own and are left alone.
+func requestTimeout(timeout time.Duration) gin.HandlerFunc {
+	return func(c *gin.Context) {
+		if isStreamPath(c.Request.URL.Path) {
+			c.Next()
+			return
+		}
+		ctx, cancel := context.WithTimeout(c.Request.Context(), timeout)
+		defer cancel()
+		c.Request = c.Request.WithContext(ctx)
+
+		w := &timeoutWriter{ResponseWriter: c.Writer, header: c.Writer.Header().Clone()}
+		c.Writer = w
+		stop := context.AfterFunc(ctx, func() {
+			if errors.Is(ctx.Err(), context.DeadlineExceeded) {
+				w.timeout()
+			}
+		})
+		defer func() {
+			stop()
+			// A handler that gave up at the deadline may return before the
+			// callback above gets to answer for it.
+			if errors.Is(ctx.Err(), context.DeadlineExceeded) {
+				w.timeout()
+			}
+			w.finish()
+			c.Writer = w.ResponseWriter
+		}()
+		c.Next()
+	}
+}
+
+// timeoutWriter stands between a handler and the client until the handler
+// starts its response. The handler gets its own header map, copied over
+// when it first writes, so the deadline can answer without racing it; once
+// the deadline has answered, whatever the handler writes is dropped.
+type timeoutWriter struct {
+	gin.ResponseWriter
+	mu       sync.Mutex
+	header   http.Header
+	started  bool
+	timedOut bool
+}
+
+func (w *timeoutWriter) Header() http.Header {
+	return w.header
+}
+
+func (w *timeoutWriter) WriteHeader(code int) {
+	w.mu.Lock()
+	defer w.mu.Unlock()
+	if !w.timedOut {
+		w.ResponseWriter.WriteHeader(code)
+	}
+}
+
+func (w *timeoutWriter) WriteHeaderNow() {
+	w.mu.Lock()
+	defer w.mu.Unlock()
+	if w.start() {
+		w.ResponseWriter.WriteHeaderNow()
+	}
+}
+
+func (w *timeoutWriter) Write(p []byte) (int, error) {
+	w.mu.Lock()
+	defer w.mu.Unlock()
+	if !w.start() {
+		return 0, http.ErrHandlerTimeout
+	}
+	return w.ResponseWriter.Write(p)
+}
+
+func (w *timeoutWriter) WriteString(s string) (int, error) {
+	w.mu.Lock()
+	defer w.mu.Unlock()
+	if !w.start() {
+		return 0, http.ErrHandlerTimeout
+	}
+	return w.ResponseWriter.WriteString(s)
+}
+
+func (w *timeoutWriter) Flush() {
+	w.mu.Lock()
+	defer w.mu.Unlock()
+	if w.start() {
+		w.ResponseWriter.Flush()
+	}
+}
+
+func (w *timeoutWriter) Status() int {
+	w.mu.Lock()
+	defer w.mu.Unlock()
+	return w.ResponseWriter.Status()
+}
+
+func (w *timeoutWriter) Size() int {
+	w.mu.Lock()
+	defer w.mu.Unlock()
+	return w.ResponseWriter.Size()
+}
+
+func (w *timeoutWriter) Written() bool {
+	w.mu.Lock()
+	defer w.mu.Unlock()
+	return w.ResponseWriter.Written()
+}
+
+// start commits the handler's headers the first time it writes, after
+// which the deadline no longer applies. It reports false once the deadline
+// has answered instead. Callers hold mu.
+func (w *timeoutWriter) start() bool {
+	if w.timedOut {
+		return false
+	}
+	if !w.started {
+		w.started = true
+		header := w.ResponseWriter.Header()
+		clear(header)
+		maps.Copy(header, w.header)
+	}
+	return true
+}
+
+// finish hands the response back to gin when the handler returns, with the
+// handler's headers in place even if it never wrote a body.
+func (w *timeoutWriter) finish() {
+	w.mu.Lock()
+	defer w.mu.Unlock()
+	w.start()
+}
+
+// timeout answers 503 unless the handler has already started. The
+// connection is closed afterwards, since the handler may still be busy.
+func (w *timeoutWriter) timeout() {
+	w.mu.Lock()
+	defer w.mu.Unlock()
+	if w.started || w.timedOut {
+		return
+	}
+	w.timedOut = true
+	metrics.timeouts.Add(1)
+
+	header := w.ResponseWriter.Header()
+	header.Set("Content-Type", "application/json; charset=utf-8")
+	header.Set("Content-Length", strconv.Itoa(len(timeoutBody)))
+	header.Set("Connection", "close")
+	w.ResponseWriter.WriteHeader(http.StatusServiceUnavailable)
+	_, _ = w.ResponseWriter.Write(timeoutBody)
+	w.ResponseWriter.Flush()
+}
+
+func (m *serverMetrics) middleware() gin.HandlerFunc {
+	return func(c *gin.Context) {
+		start := time.Now()
+		c.Next()
+		m.recordLatency(time.Since(start))
+		m.countRoute(routeKind(c.FullPath()))
+		m.countStatus(c.Writer.Status())
+		size := max(c.Writer.Size(), 0)
+		m.bytesSent.Add(uint64(size))
+		m.sizeBuckets[sizeBucket(size)].Add(1)
+	}
+}
+
+// sizeBucket maps a response size to one of the power-of-two buckets from
+// 1KiB up to 16MiB, with anything larger landing in the final +Inf bucket.
+func sizeBucket(size int) int {
+	if size <= 1024 {
+		return 0
+	}
+	return min(bits.Len(uint(size-1))-10, sizeBucketCount)
+}
+
+func sizeBucketBound(bucket int) int {
+	return 1024 << bucket
+}
+
+// recordCompression tracks a compressed response alongside the size it
+// would have had uncompressed.
+func (m *serverMetrics) recordCompression(uncompressed, compressed int) {
+	m.uncompressedBytes.Add(uint64(uncompressed))
+	m.compressedBytes.Add(uint64(compressed))
+}
+
+// compressionRatio reports compressed bytes as a fraction of their
+// uncompressed size, or 0 if nothing has been compressed yet.
+func (m *serverMetrics) compressionRatio() float64 {
+	uncompressed := m.uncompressedBytes.Load()
+	if uncompressed == 0 {
+		return 0
+	}
+	return float64(m.compressedBytes.Load()) / float64(uncompressed)
+}
+
+func (m *serverMetrics) countStatus(status int) {
+	class := status/100 - 2
+	if class >= 0 && class < len(m.statusClasses) {
+		m.statusClasses[class].Add(1)
+	}
+}
+
+func (m *serverMetrics) statusCounts() []labeledCount {
+	counts := make([]labeledCount, len(m.statusClasses))
+	for i := range m.statusClasses {
+		counts[i] = labeledCount{Label: statusClassLabels[i], Count: m.statusClasses[i].Load()}
+	}
+	return counts
+}
+
+func routeKind(fullPath string) string {
+	switch fullPath {
+	case "":
+		return "not_found"
+	case "/":
+		return "docs"
+	}
+
+	var parts []string
+	for _, segment := range strings.Split(strings.Trim(fullPath, "/"), "/") {
+		if strings.HasPrefix(segment, "*") || strings.HasPrefix(segment, ":") {
+			continue
+		}
+		parts = append(parts, strings.ToLower(segment))
+	}
+	kind := strings.Join(parts, "_")
+	if strings.HasPrefix(fullPath, "/Gary/") || strings.HasPrefix(fullPath, "/Goober/") {
+		kind += "_file"
+	}
+	return kind
+}
+
+func (m *serverMetrics) countRoute(kind string) {
+	counter, ok := m.routeRequests.Load(kind)
+	if !ok {
+		counter, _ = m.routeRequests.LoadOrStore(kind, new(atomic.Uint64))
+	}
+	counter.(*atomic.Uint64).Add(1)
+}
+
+func (m *serverMetrics) routeCounts() []labeledCount {
+	var counts []labeledCount
+	m.routeRequests.Range(func(key, value any) bool {
+		counts = append(counts, labeledCount{Label: key.(string), Count: value.(*atomic.Uint64).Load()})
+		return true
+	})
+	sort.Slice(counts, func(i, j int) bool { return counts[i].Label < counts[j].Label })
+	return counts
+}
+
+func latencyBucket(micros uint64) int {
+	return min(bits.Len64(micros), latencyBucketCount-1)
+}
+
+func (m *serverMetrics) recordLatency(latency time.Duration) {
+	micros := uint64(latency.Microseconds())
+	m.requests.Add(1)
+	m.totalLatencyUs.Add(micros)
+	m.intervalRequests.Add(1)
+	m.intervalLatencyUs.Add(micros)
+	m.latencyBuckets[latencyBucket(micros)].Add(1)
+}
+
+// responseTimePercentile reports the upper bound of the power-of-two
+// microsecond bucket that contains the given quantile.
+func (m *serverMetrics) responseTimePercentile(quantile float64) time.Duration {
+	var counts [latencyBucketCount]uint64
+	var total uint64
+	for i := range m.latencyBuckets {
+		counts[i] = m.latencyBuckets[i].Load()
+		total += counts[i]
+	}
+	if total == 0 {
+		return 0
+	}
+
+	target := uint64(math.Ceil(quantile * float64(total)))
+	var seen uint64
+	for i, count := range counts {
+		seen += count
+		if seen >= target {
+			return time.Duration(uint64(1)<<i) * time.Microsecond
+		}
+	}
+	return time.Duration(uint64(1)<<(latencyBucketCount-1)) * time.Microsecond
+}
+
+func (m *serverMetrics) responseTimeP50() time.Duration {
+	return m.responseTimePercentile(0.50)
+}
+
+func (m *serverMetrics) responseTimeP95() time.Duration {
+	return m.responseTimePercentile(0.95)
+}
+
+func (m *serverMetrics) responseTimeP99() time.Duration {
+	return m.responseTimePercentile(0.99)
+}
+
+// addressClass sorts a client address into loopback, private or public,
+// indexing addressClassLabels. Addresses without an IP, such as Unix
+// sockets, can only come from this host and count as loopback.
+func addressClass(addr net.Addr) int {
+	host, _, err := net.SplitHostPort(addr.String())
+	if err != nil {
+		host = addr.String()
+	}
+	ip := net.ParseIP(host)
+	switch {
+	case ip == nil || ip.IsLoopback():
+		return 0
+	case ip.IsPrivate() || ip.IsLinkLocalUnicast():
+		return 1
+	default:
+		return 2
+	}
+}
+
+func (m *serverMetrics) trackConnState(conn net.Conn, state http.ConnState) {
+	switch state {
+	case http.StateNew:
+		m.activeConnections.Add(1)
+		m.addressClasses[addressClass(conn.RemoteAddr())].Add(1)
+	case http.StateClosed, http.StateHijacked:
+		m.activeConnections.Add(-1)
+	}
+}
+
+func (m *serverMetrics) resetInterval() (requests, latencyUs uint64) {
+	return m.intervalRequests.Swap(0), m.intervalLatencyUs.Swap(0)
+}
+
+var stopMetricsLog = func() {}
+
+// logMetricsPeriodically logs a snapshot every interval, replacing any
+// logger started by an earlier call.
+func logMetricsPeriodically(interval time.Duration) {
+	stopMetricsLog()
+	stopMetricsLog = func() {}
+	if interval <= 0 {
+		return
+	}
+	done := make(chan struct{})
+	stopMetricsLog = sync.OnceFunc(func() { close(done) })
+	go func() {
+		ticker := time.NewTicker(interval)
+		defer ticker.Stop()
+		for {
+			select {
+			case <-ticker.C:
+			case <-done:
+				return
+			}
+			requests, latencyUs := metrics.resetInterval()
+			var average time.Duration
+			if requests > 0 {
+				average = time.Duration(latencyUs/requests) * time.Microsecond
+			}
+			slog.Info("metrics snapshot",
+				"interval", interval,
+				"requests", requests,
+				"rps", float64(requests)/interval.Seconds(),
+				"avg_latency", average,
+				"total_requests", metrics.requestCount())
+		}
+	}()
+}
+
+func (m *serverMetrics) uptime() time.Duration {
+	return time.Since(m.startTime)
+}
+
+func (m *serverMetrics) requestCount() uint64 {
+	return m.requests.Load()
+}
+
+func (m *serverMetrics) requestsPerSecond() float64 {
+	seconds := m.uptime().Seconds()
+	if seconds <= 0 {
+		return 0
+	}
+	return float64(m.requestCount()) / seconds
+}
+
+func (m *serverMetrics) averageLatency() time.Duration {
+	count := m.requestCount()
+	if count == 0 {
+		return 0
+	}
+	return time.Duration(m.totalLatencyUs.Load()/count) * time.Microsecond
+}
+
+type limitListener struct {
+	net.Listener
+	slots  chan struct{}
+	reject bool
+}
+
+type limitConn struct {
+	net.Conn
+	once    sync.Once
+	release func()
+}
+
+func newLimitListener(l net.Listener, maxConnections int, reject bool) net.Listener {
+	return &limitListener{
+		Listener: l,
+		slots:    make(chan struct{}, maxConnections),
+		reject:   reject,
+	}
+}
+
+func (l *limitListener) Accept() (net.Conn, error) {
+	for {
+		if !l.reject {
+			l.slots <- struct{}{}
+		}
+
+		conn, err := l.Listener.Accept()
+		if err != nil {
+			if !l.reject {
+				<-l.slots
+			}
+			return nil, err
+		}
+
+		if l.reject {
+			select {
+			case l.slots <- struct{}{}:
+			default:
+				conn.Close()
+				continue
+			}
+		}
+		return &limitConn{Conn: conn, release: func() { <-l.slots }}, nil
+	}
+}
+
+func (c *limitConn) Close() error {
+	err := c.Conn.Close()
+	c.once.Do(c.release)
+	return err
+}
+
+// resourceSubroutes are the /gary/<segment> routes; any other segment is
+// taken as a filename for the /Gary file route. Under the exact-case /Gary/
+// and /Goober/ prefixes a real file wins, so /Gary/image.png stays the file.
+var resourceSubroutes = map[string]struct{}{
+	"image":     {},
+	"count":     {},
+	"list":      {},
+	"stream":    {},
+	"bandwidth": {},
+}
+
+func normalizeResourcePath(path string) string {
+	original, rest, hasRest := strings.Cut(strings.TrimPrefix(path, "/"), "/")
+	resource := strings.ToLower(original)
+	if resource == "image" && hasRest {
+		name, tail, hasTail := strings.Cut(rest, "/")
+		name = strings.ToLower(name)
+		if name != "gary" && name != "goober" {
+			return path
+		}
+		if !hasTail {
+			return "/image/" + name
+		}
+		return "/image/" + name + "/" + tail
+	}
+	if resource != "gary" && resource != "goober" {
+		return path
+	}
+	if !hasRest {
+		return "/" + resource
+	}
+	if rest == "" {
+		return "/" + resource + "/"
+	}
+
+	fileRoute := strings.ToUpper(resource[:1]) + resource[1:]
+	segment, _, _ := strings.Cut(rest, "/")
+	if original == fileRoute && resourceFor(resource).has(segment) {
+		return path
+	}
+	lowered := strings.ToLower(segment)
+	if _, ok := resourceSubroutes[lowered]; ok || strings.HasPrefix(lowered, "image.") {
+		return "/" + resource + "/" + lowered + rest[len(segment):]
+	}
+	return "/" + fileRoute + "/" + rest
+}
+
+func resourceFor(name string) *imageResource {
+	if name == "goober" {
+		return gooberResource
+	}
+	return garyResource
+}
+
+func withNormalizedPaths(next http.Handler) http.Handler {
+	return http.HandlerFunc(func(w http.ResponseWriter, req *http.Request) {
+		req.URL.Path = normalizeResourcePath(req.URL.Path)
+		next.ServeHTTP(w, req)
+	})
+}
+
+// limitURILength answers 414 for a request URI longer than maxLength. It
+// runs as middleware rather than in front of the router so the refusal
+// still gets a request ID, the security headers and a place in the metrics.
+func limitURILength(maxLength int) gin.HandlerFunc {
+	return func(c *gin.Context) {
+		if len(c.Request.RequestURI) > maxLength {
+			respondError(c, http.StatusRequestURITooLong, "uri_too_long", "request URI too long")
+		}
+	}
+}
+
+func listen(address string) (net.Listener, error) {
+	socketPath, isUnix := strings.CutPrefix(address, "unix:")
+	if !isUnix {
+		return net.Listen("tcp", address)
+	}
+
+	if info, err := os.Stat(socketPath); err == nil && info.Mode()&os.ModeSocket != 0 {
+		if err := removeStaleSocket(socketPath); err != nil {
+			return nil, err
+		}
+	}
+	return net.Listen("unix", socketPath)
+}
+
+// startServer binds cfg.ListenAddress and serves handler on it in the
+// background. It returns the address actually bound, which tells a caller
+// that asked for port 0 where to connect, and a channel that receives the
+// error Serve returns once the server stops.
+func startServer(cfg Config, handler http.Handler) (*http.Server, string, <-chan error, error) {
+	listener, err := listen(cfg.ListenAddress)
+	if err != nil {
+		return nil, "", nil, err
+	}
+	if cfg.MaxConnections > 0 {
+		reject := cfg.ConnectionLimitPolicy == "reject"
+		listener = newLimitListener(listener, cfg.MaxConnections, reject)
+	}
+
+	server := &http.Server{
+		Handler:           handler,
+		ConnState:         metrics.trackConnState,
+		ReadHeaderTimeout: cfg.HeaderReadTimeout,
+		IdleTimeout:       cfg.IdleTimeout,
+		MaxHeaderBytes:    cfg.MaxHeaderBytes,
+	}
+	server.SetKeepAlivesEnabled(cfg.KeepAlive)
+
+	served := make(chan error, 1)
+	go func() { served <- server.Serve(listener) }()
+	return server, listener.Addr().String(), served, nil
+}
+
+// removeStaleSocket deletes a socket file left behind by a server that is
+// gone. Only a refused connection proves that; a socket that answers, or
+// one that can't be probed, is left for its owner.
+func removeStaleSocket(socketPath string) error {
+	conn, err := net.DialTimeout("unix", socketPath, socketProbeTimeout)
+	if err == nil {
+		conn.Close()
+		return fmt.Errorf("socket %s is in use by another process", socketPath)
+	}
+	if !errors.Is(err, syscall.ECONNREFUSED) {
+		return fmt.Errorf("could not check socket %s: %w", socketPath, err)
+	}
+	if err := os.Remove(socketPath); err != nil {
+		return fmt.Errorf("could not remove stale socket %s: %w", socketPath, err)
+	}
+	return nil
+}
+
+type Config struct {
+	ListenAddress         string
+	GaryDir               string
+	GooberDir             string
+	GaryURL               string
+	GooberURL             string
+	QuotesFile            string
+	JokesFile             string
+	IndexFile             string
+	FaviconFile           string
+	RootBehavior          string
+	RootRedirectURL       string
+	NotFoundBody          string
+	NotFoundContentType   string
+	RequestTimeout        time.Duration
+	HeaderReadTimeout     time.Duration
+	IdleTimeout           time.Duration
+	KeepAlive             bool
+	MaxHeaderBytes        int
+	MaxRequestBodyBytes   int
+	MaxConnections        int
+	ConnectionLimitPolicy string
+	APIKeys               []string
+	CacheControl          map[string]string
+	AdminToken            string
+	FrameOptions          string
+	ReferrerPolicy        string
+	DocsCSP               string
+	WorkerThreads         int
+	ImageWorkers          int
+	FileReadAttempts      int
+	MaxURILength          int
+	MetricsLogInterval    time.Duration
+	ImageRedirect         bool
+	ShuffleBag            bool
+	ValidateImageContent  bool
+	EmptyResourceBehavior string
+	GaryDefaultImage      string
+	GooberDefaultImage    string
+	LogLevel              string
+	LogFormat             string
+	MaxImageBytes         int
+	ManifestURL           string
+	StartupProgress       bool
+	StartupReport         string
+	TextEncoding          string
+}
+
+func defaultConfig() Config {
+	return Config{
+		ListenAddress:         ":8080",
+		NotFoundContentType:   "text/plain; charset=utf-8",
+		RequestTimeout:        defaultRequestTimeout,
+		HeaderReadTimeout:     defaultHeaderTimeout,
+		IdleTimeout:           defaultIdleTimeout,
+		KeepAlive:             true,
+		MaxHeaderBytes:        64 << 10,
+		MaxRequestBodyBytes:   1 << 20,
+		ConnectionLimitPolicy: "wait",
+		RootBehavior:          "docs",
+		EmptyResourceBehavior: "default",
+		TextEncoding:          "lossy",
+		WorkerThreads:         runtime.NumCPU(),
+		ImageWorkers:          runtime.NumCPU(),
+		FileReadAttempts:      3,
+		MaxURILength:          2048,
+		MetricsLogInterval:    time.Minute,
+		GaryDefaultImage:      defaultGaryImg,
+		GooberDefaultImage:    defaultGooberImg,
+		LogLevel:              "info",
+		LogFormat:             "text",
+		FrameOptions:          "DENY",
+		ReferrerPolicy:        "no-referrer",
+		DocsCSP:               defaultDocsCSP,
+	}
+}
+
+func configFromEnv() Config {
+	cfg := defaultConfig()
+
+	var port, bindAddress string
+	envString("PORT", &port)
+	envString("BIND_ADDRESS", &bindAddress)
+	if port != "" || bindAddress != "" {
+		if port == "" {
+			port = "8080"
+		}
+		cfg.ListenAddress = net.JoinHostPort(strings.Trim(bindAddress, "[]"), port)
+	}
+	envString("LISTEN", &cfg.ListenAddress)
+	envString("GARY_DIR", &cfg.GaryDir)
+	envString("GOOBER_DIR", &cfg.GooberDir)
+	envString("GARYURL", &cfg.GaryURL)
+	envString("GOOBERURL", &cfg.GooberURL)
+	envString("QUOTES_FILE", &cfg.QuotesFile)
+	envString("JOKES_FILE", &cfg.JokesFile)
+	envString("INDEX_FILE", &cfg.IndexFile)
+	envString("FAVICON_FILE", &cfg.FaviconFile)
+	envString("ROOT_BEHAVIOR", &cfg.RootBehavior)
+	envString("ROOT_REDIRECT_URL", &cfg.RootRedirectURL)
+	envString("NOT_FOUND_BODY", &cfg.NotFoundBody)
+	envString("NOT_FOUND_CONTENT_TYPE", &cfg.NotFoundContentType)
+	envDuration("REQUEST_TIMEOUT", &cfg.RequestTimeout)
+	envDuration("HEADER_READ_TIMEOUT", &cfg.HeaderReadTimeout)
+	envDuration("IDLE_TIMEOUT", &cfg.IdleTimeout)
+	envBool("KEEP_ALIVE", &cfg.KeepAlive)
+	envInt("MAX_HEADER_BYTES", &cfg.MaxHeaderBytes)
+	envInt("MAX_REQUEST_BODY_BYTES", &cfg.MaxRequestBodyBytes)
+	envInt("MAX_CONNECTIONS", &cfg.MaxConnections)
+	envString("CONNECTION_LIMIT_POLICY", &cfg.ConnectionLimitPolicy)
+	envList("API_KEYS", &cfg.APIKeys)
+	envMap("CACHE_CONTROL", &cfg.CacheControl)
+	envString("ADMIN_TOKEN", &cfg.AdminToken)
+	envString("FRAME_OPTIONS", &cfg.FrameOptions)
+	envString("REFERRER_POLICY", &cfg.ReferrerPolicy)
+	envString("DOCS_CSP", &cfg.DocsCSP)
+	envInt("WORKER_THREADS", &cfg.WorkerThreads)
+	envInt("IMAGE_WORKERS", &cfg.ImageWorkers)
+	envInt("FILE_READ_ATTEMPTS", &cfg.FileReadAttempts)
+	envInt("MAX_URI_LENGTH", &cfg.MaxURILength)
+	envDuration("METRICS_LOG_INTERVAL", &cfg.MetricsLogInterval)
+	envBool("IMAGE_REDIRECT", &cfg.ImageRedirect)
+	envBool("SHUFFLE_BAG", &cfg.ShuffleBag)
+	envBool("VALIDATE_IMAGE_CONTENT", &cfg.ValidateImageContent)
+	envString("EMPTY_RESOURCE_BEHAVIOR", &cfg.EmptyResourceBehavior)
+	envString("GARY_DEFAULT_IMAGE", &cfg.GaryDefaultImage)
+	envString("GOOBER_DEFAULT_IMAGE", &cfg.GooberDefaultImage)
+	envString("LOG_LEVEL", &cfg.LogLevel)
+	envString("LOG_FORMAT", &cfg.LogFormat)
+	envInt("MAX_IMAGE_BYTES", &cfg.MaxImageBytes)
+	envString("MANIFEST_URL", &cfg.ManifestURL)
+	envBool("STARTUP_PROGRESS", &cfg.StartupProgress)
+	envString("STARTUP_REPORT", &cfg.StartupReport)
+	envString("TEXT_ENCODING", &cfg.TextEncoding)
+
+	return cfg
+}
+
+func (cfg Config) validate() error {
+	dirs := []struct {
+		key string
+		dir string
+	}{
+		{"GARY_DIR", cfg.GaryDir},
+		{"GOOBER_DIR", cfg.GooberDir},
+	}
+	for _, entry := range dirs {
+		for _, dir := range imageDirs(entry.dir) {
+			info, err := os.Stat(dir)
+			if err != nil {
+				return fmt.Errorf("%s: %w", entry.key, err)
+			}
+			if !info.IsDir() {
+				return fmt.Errorf("%s: %s is not a directory", entry.key, dir)
+			}
+		}
+	}
+
+	if err := validateListenAddress(cfg.ListenAddress); err != nil {
+		return err
+	}
+	if cfg.WorkerThreads < 1 {
+		return fmt.Errorf("WORKER_THREADS must be at least 1, got %d", cfg.WorkerThreads)
+	}
+	if cfg.ImageWorkers < 1 {
+		return fmt.Errorf("IMAGE_WORKERS must be at least 1, got %d", cfg.ImageWorkers)
+	}
+	if cfg.MaxConnections < 0 {
+		return fmt.Errorf("MAX_CONNECTIONS must not be negative, got %d", cfg.MaxConnections)
+	}
+	if cfg.ConnectionLimitPolicy != "wait" && cfg.ConnectionLimitPolicy != "reject" {
+		return fmt.Errorf("CONNECTION_LIMIT_POLICY must be wait or reject, got %q", cfg.ConnectionLimitPolicy)
+	}
+	if cfg.TextEncoding != "lossy" && cfg.TextEncoding != "strict" {
+		return fmt.Errorf("TEXT_ENCODING must be lossy or strict, got %q", cfg.TextEncoding)
+	}
+	switch cfg.EmptyResourceBehavior {
+	case "default", "not_found", "error":
+	default:
+		return fmt.Errorf("EMPTY_RESOURCE_BEHAVIOR must be default, not_found or error, got %q", cfg.EmptyResourceBehavior)
+	}
+	switch cfg.RootBehavior {
+	case "docs", "json":
+	case "redirect":
+		if cfg.RootRedirectURL == "" {
+			return errors.New("ROOT_REDIRECT_URL is required when ROOT_BEHAVIOR is redirect")
+		}
+	default:
+		return fmt.Errorf("ROOT_BEHAVIOR must be docs, redirect or json, got %q", cfg.RootBehavior)
+	}
+	return nil
+}
+
+func initLogging(cfg Config) {
+	var level slog.Level
+	if err := level.UnmarshalText([]byte(cfg.LogLevel)); err != nil {
+		level = slog.LevelInfo
+	}
+
+	options := &slog.HandlerOptions{Level: level}
+	var handler slog.Handler
+	if strings.EqualFold(cfg.LogFormat, "json") {
+		handler = slog.NewJSONHandler(os.Stdout, options)
+	} else {
+		handler = slog.NewTextHandler(os.Stdout, options)
+	}
+	slog.SetDefault(slog.New(handler))
+}
+
+func validateListenAddress(address string) error {
+	if strings.HasPrefix(address, "unix:") {
+		return nil
+	}
+
+	host, port, err := net.SplitHostPort(address)
+	if err != nil {
+		return fmt.Errorf("invalid listen address %q: %w", address, err)
+	}
+	if number, err := strconv.Atoi(port); err != nil || number < 0 || number > 65535 {
+		return fmt.Errorf("invalid port %q in listen address %q", port, address)
+	}
+	if host != "" && net.ParseIP(host) == nil && !isValidHostname(host) {
+		return fmt.Errorf("invalid bind address %q: not an IP address or hostname", host)
+	}
+	return nil
+}
+
+func isValidHostname(host string) bool {
+	if len(host) > 253 {
+		return false
+	}
+	for _, label := range strings.Split(strings.TrimSuffix(host, "."), ".") {
+		if label == "" || len(label) > 63 || label[0] == '-' || label[len(label)-1] == '-' {
+			return false
+		}
+		for _, r := range label {
+			if !(r >= 'a' && r <= 'z' || r >= 'A' && r <= 'Z' || r >= '0' && r <= '9' || r == '-') {
+				return false
+			}
+		}
+	}
+	return true
+}
+
+func currentConfig() *Config {
+	return activeConfig.Load()
+}
+
+var (
+	dotenvMu   sync.Mutex
+	dotenvKeys = make(map[string]struct{})
+)
+
+// loadDotenv fills in variables from .env that the process environment
+// doesn't already set. Whatever an earlier call took from the file is
+// cleared first, so a key deleted from .env falls back to its default on
+// reload instead of lingering.
+func loadDotenv() {
+	dotenvMu.Lock()
+	defer dotenvMu.Unlock()
+	for key := range dotenvKeys {
+		os.Unsetenv(key)
+	}
+	clear(dotenvKeys)
+
+	values, err := godotenv.Read()
+	if err != nil {
+		if !errors.Is(err, os.ErrNotExist) {
+			slog.Warn("could not read .env", "error", err)
+		}
+		return
+	}
+	for key, value := range values {
+		if _, set := os.LookupEnv(key); set {
+			continue
+		}
+		os.Setenv(key, value)
+		dotenvKeys[key] = struct{}{}
+	}
+}
+
+// restartOnlyChanges lists the settings that differ between previous and
+// next but are only read when the listener and server are created.
+func restartOnlyChanges(previous, next *Config) []string {
+	var keys []string
+	check := func(key string, changed bool) {
+		if changed {
+			keys = append(keys, key)
+		}
+	}
+	check("LISTEN", previous.ListenAddress != next.ListenAddress)
+	check("MAX_CONNECTIONS", previous.MaxConnections != next.MaxConnections)
+	check("CONNECTION_LIMIT_POLICY", previous.ConnectionLimitPolicy != next.ConnectionLimitPolicy)
+	check("HEADER_READ_TIMEOUT", previous.HeaderReadTimeout != next.HeaderReadTimeout)
+	check("IDLE_TIMEOUT", previous.IdleTimeout != next.IdleTimeout)
+	check("KEEP_ALIVE", previous.KeepAlive != next.KeepAlive)
+	check("MAX_HEADER_BYTES", previous.MaxHeaderBytes != next.MaxHeaderBytes)
+	return keys
+}
+
+var reloadMu sync.Mutex
+
+// reloadConfig re-reads the environment and .env and applies the result:
+// logging, worker threads and the metrics log change in place, and a
+// handler built from the new settings replaces the old one, which finishes
+// the requests it already has. Listener settings are only reported.
+func reloadConfig() {
+	reloadMu.Lock()
+	defer reloadMu.Unlock()
+
+	loadDotenv()
+	next := configFromEnv()
+	if err := next.validate(); err != nil {
+		slog.Error("reload failed, keeping previous configuration", "error", err)
+		return
+	}
+
+	previous := currentConfig()
+	initLogging(next)
+	runtime.GOMAXPROCS(next.WorkerThreads)
+	if next.MetricsLogInterval != previous.MetricsLogInterval {
+		logMetricsPeriodically(next.MetricsLogInterval)
+	}
+	reloadResources(next)
+	activeConfig.Store(&next)
+	handler := newHandler(next)
+	activeHandler.Store(&handler)
+
+	for _, key := range restartOnlyChanges(previous, &next) {
+		slog.Warn("setting changed; restart to apply", "setting", key)
+	}
+	slog.Info("configuration reloaded",
+		"gary_images", garyResource.count(),
+		"goober_images", gooberResource.count(),
+		"quotes", checkTextFile("QUOTES_FILE", quoteLines),
+		"jokes", checkTextFile("JOKES_FILE", jokeLines))
+}
+
+func watchReloadSignal() {
+	reload := make(chan os.Signal, 1)
+	signal.Notify(reload, syscall.SIGHUP)
+	go func() {
+		for range reload {
+			reloadConfig()
+		}
+	}()
+}
+
+func envString(key string, target *string) {
+	if value := os.Getenv(key); value != "" {
+		*target = value
+	}
+}
+
+// envMap parses "key=value;key=value" pairs. Semicolons separate entries
+// so values may themselves contain commas.
+func envMap(key string, target *map[string]string) {
+	value := os.Getenv(key)
+	if value == "" {
+		return
+	}
+	items := make(map[string]string)
+	for _, item := range strings.Split(value, ";") {
+		name, setting, ok := strings.Cut(item, "=")
+		if name = strings.TrimSpace(name); ok && name != "" {
+			items[name] = strings.TrimSpace(setting)
+		}
+	}
+	*target = items
+}
+
+func envList(key string, target *[]string) {
+	value := os.Getenv(key)
+	if value == "" {
+		return
+	}
+	var items []string
+	for _, item := range strings.Split(value, ",") {
+		if item = strings.TrimSpace(item); item != "" {
+			items = append(items, item)
+		}
+	}
+	*target = items
+}
+
+func envBool(key string, target *bool) {
+	value := os.Getenv(key)
+	if value == "" {
+		return
+	}
+	parsed, err := strconv.ParseBool(value)
+	if err != nil {
+		slog.Warn("invalid boolean in environment", "key", key, "value", value, "using", *target, "error", err)
+		return
+	}
+	*target = parsed
+}
+
+func envInt(key string, target *int) {
+	value := os.Getenv(key)
+	if value == "" {
+		return
+	}
+	number, err := strconv.Atoi(value)
+	if err != nil {
+		slog.Warn("invalid integer in environment", "key", key, "value", value, "using", *target, "error", err)
+		return
+	}
+	*target = number
+}
+
+func envDuration(key string, target *time.Duration) {
+	value := os.Getenv(key)
+	if value == "" {
+		return
+	}
+	duration, err := time.ParseDuration(value)
+	if err != nil {
+		slog.Warn("invalid duration in environment", "key", key, "value", value, "using", *target, "error", err)
+		return
+	}
+	*target = duration
+}
+
+func registerImageMimeTypes() {
+	for ext, contentType := range imageMimeTypes {
+		if err := mime.AddExtensionType(ext, contentType); err != nil {
+			slog.Warn("failed to register MIME type", "ext", ext, "error", err)
+		}
+	}
+}
+
+// imageFile is an open image as returned by an imageStore.
+type imageFile interface {
+	io.ReadSeekCloser
+	Stat() (os.FileInfo, error)
+}
+
+// imageStore is where image listings and bytes come from. A resource's
+// location is opaque to everything but the store: for fsImageStore it is a
+// directory list, while a cloud-backed store could read a bucket prefix.
+// Names passed to open are slash-separated and already cleaned, and a
+// missing image is reported with an error wrapping fs.ErrNotExist.
+type imageStore interface {
+	scan(location string, previous imageScan) imageScan
+	open(location, name string) (imageFile, error)
+	available(location string) bool
+}
+
+// fsImageStore serves images from local directories.
+type fsImageStore struct{}
+
+func (fsImageStore) scan(location string, previous imageScan) imageScan {
+	return cacheFileNames(location, previous)
+}
+
+func (fsImageStore) open(location, name string) (imageFile, error) {
+	file, err := openWithRetry(resolveImagePath(location, filepath.FromSlash(name)), currentConfig().FileReadAttempts)
+	if err != nil {
+		return nil, err
+	}
+	return file, nil
+}
+
+func (fsImageStore) available(location string) bool {
+	return imageDirsAvailable(location)
+}
+
+var store imageStore = fsImageStore{}
+
+// imageDirs splits a directory list such as "/srv/gary:/mnt/uploads" into
+// the directories it names, in search order.
+func imageDirs(dirList string) []string {
+	return filepath.SplitList(dirList)
+}
+
+// cacheFileNames lists the files across every directory in dirList, each
+// name once, along with the one modified most recently and the dimensions
+// of those in a format the standard library can decode. A file whose size
+// and modification time match previous keeps the dimensions measured then
+// instead of being decoded again.
+func cacheFileNames(dirList string, previous imageScan) imageScan {
+	scan := imageScan{sizes: make(map[string]imageSize), files: make(map[string]imageFileInfo)}
+	paths := make(map[string]string)
+	var latestTime time.Time
+	progress := currentConfig().StartupProgress
+	for _, dir := range imageDirs(dirList) {
+		files, err := os.ReadDir(dir)
+		if err != nil {
+			slog.Error("failed to read directory", "dir", dir, "error", err)
+			continue
+		}
+
+		var scannedBytes int64
+		lastReport := time.Now()
+		for i, file := range files {
+			if progress && time.Since(lastReport) >= scanProgressInterval {
+				slog.Info("scanning images", "dir", dir, "scanned", i, "total", len(files), "bytes", scannedBytes)
+				lastReport = time.Now()
+			}
+			if file.IsDir() {
+				continue
+			}
+			info, err := file.Info()
+			if err == nil {
+				scannedBytes += info.Size()
+			}
+			if err == nil && info.Size() == 0 {
+				slog.Warn("skipping empty image file", "dir", dir, "file", file.Name())
+				continue
+			}
+			if _, dup := paths[file.Name()]; !dup {
+				scan.names = append(scan.names, file.Name())
+			}
+			paths[file.Name()] = filepath.Join(dir, file.Name())
+			if err != nil {
+				delete(scan.files, file.Name())
+				continue
+			}
+			scan.files[file.Name()] = imageFileInfo{bytes: info.Size(), modTime: info.ModTime()}
+			if info.ModTime().After(latestTime) {
+				scan.latest, latestTime = file.Name(), info.ModTime()
+			}
+		}
+	}
+
+	for name, path := range paths {
+		info, known := scan.files[name]
+		if old, ok := previous.files[name]; known && ok && old.bytes == info.bytes && old.modTime.Equal(info.modTime) {
+			if size, ok := previous.sizes[name]; ok {
+				scan.sizes[name] = size
+			}
+			continue
+		}
+		if size, ok := readImageSize(path); ok {
+			scan.sizes[name] = size
+		}
+	}
+	return scan
+}
+
+// readImageSize decodes just the header of a PNG, JPEG or GIF file.
+func readImageSize(path string) (imageSize, bool) {
+	file, err := os.Open(path)
+	if err != nil {
+		return imageSize{}, false
+	}
+	defer file.Close()
+
+	config, _, err := image.DecodeConfig(file)
+	if err != nil {
+		return imageSize{}, false
+	}
+	return imageSize{Width: config.Width, Height: config.Height}, true
+}
+
+var unavailableDirs sync.Map
+
+// imageDirsAvailable reports whether every directory in dirList can still
+// be reached, logging each outage once instead of on every request.
+func imageDirsAvailable(dirList string) bool {
+	available := true
+	for _, dir := range imageDirs(dirList) {
+		info, err := os.Stat(dir)
+		if err == nil && info.IsDir() {
+			if _, wasDown := unavailableDirs.LoadAndDelete(dir); wasDown {
+				slog.Info("image directory available again", "dir", dir)
+			}
+			continue
+		}
+		available = false
+		if _, logged := unavailableDirs.LoadOrStore(dir, struct{}{}); !logged {
+			slog.Error("image directory unavailable", "dir", dir, "error", err)
+		}
+	}
+	return available
+}
+
+// resolveImagePath finds name in the last directory of dirList that has
+// it, so later directories override earlier ones on a filename collision.
+// Like cacheFileNames it passes over empty files and directories, so the
+// file served is the one the scan listed.
+func resolveImagePath(dirList, name string) string {
+	dirs := imageDirs(dirList)
+	for i := len(dirs) - 1; i > 0; i-- {
+		path := filepath.Join(dirs[i], name)
+		if info, err := os.Stat(path); err == nil && info.Mode().IsRegular() && info.Size() > 0 {
+			return path
+		}
+	}
+	if len(dirs) == 0 {
+		return filepath.Join(dirList, name)
+	}
+	return filepath.Join(dirs[0], name)
+}
+
+// randomSource picks the indexes behind every random selection, so a seeded
+// or cryptographic generator can stand in for the default math/rand one.
+type randomSource interface {
+	Intn(n int) int
+	Perm(n int) []int
+}
+
+type mathRandSource struct{}
+
+func (mathRandSource) Intn(n int) int   { return rand.Intn(n) }
+func (mathRandSource) Perm(n int) []int { return rand.Perm(n) }
+
+var rng randomSource = mathRandSource{}
+
+func getRandomFileName(images []string, defaultName string) string {
+	if len(images) == 0 {
+		return defaultName
+	}
+	return images[rng.Intn(len(images))]
+}
+
+type resourceUpdate struct {
+	res          *imageResource
+	dir          string
+	baseURL      string
+	defaultImage string
+	images       []string
+}
+
+type imageSize struct {
+	Width  int
+	Height int
+}
+
+type imageFileInfo struct {
+	bytes   int64
+	modTime time.Time
+}
+
+// imageScan is what a directory scan learns about a resource's files.
+type imageScan struct {
+	names  []string
+	latest string
+	sizes  map[string]imageSize
+	files  map[string]imageFileInfo
+}
+
+type imageManifest struct {
+	Gary   []string `json:"gary"`
+	Goober []string `json:"goober"`
+	Quotes []string `json:"quotes"`
+	Jokes  []string `json:"jokes"`
+}
+
+// swapResources scans every directory first and then replaces all of the
+// resources under a single write lock, so readers see either the old state
+// or the new one and never an empty or half-updated list.
+func swapResources(updates ...resourceUpdate) {
+	scanned := make([]imageScan, len(updates))
+	for i, update := range updates {
+		if update.images != nil {
+			scanned[i] = imageScan{names: update.images}
+			continue
+		}
+		scanned[i] = store.scan(update.dir, update.res.lastScan(update.dir))
+	}
+
+	imageCacheMu.Lock()
+	defer imageCacheMu.Unlock()
+	for i, update := range updates {
+		update.res.apply(update.dir, update.baseURL, update.defaultImage, scanned[i])
+		update.res.fromManifest = update.images != nil
+	}
+}
+
+func fetchManifest(url string) (*imageManifest, error) {
+	client := &http.Client{Timeout: manifestTimeout}
+	resp, err := client.Get(url)
+	if err != nil {
+		return nil, fmt.Errorf("could not fetch manifest %s: %w", url, err)
+	}
+	defer resp.Body.Close()
+	if resp.StatusCode != http.StatusOK {
+		return nil, fmt.Errorf("could not fetch manifest %s: status %s", url, resp.Status)
+	}
+
+	var manifest imageManifest
+	if err := json.NewDecoder(resp.Body).Decode(&manifest); err != nil {
+		return nil, fmt.Errorf("could not decode manifest %s: %w", url, err)
+	}
+	return &manifest, nil
+}
+
+var resourcesMu sync.Mutex
+
+// reloadResources runs loadResources on a serving instance, reporting not
+// ready on /ready until it finishes so a load balancer can hold traffic off
+// while the lists are replaced.
+func reloadResources(cfg Config) {
+	resourcesMu.Lock()
+	defer resourcesMu.Unlock()
+	wasReady := cacheReady.Swap(false)
+	defer cacheReady.Store(wasReady)
+	loadResources(cfg)
+}
+
+// markReady starts reporting ready on /ready. It waits out a reload in
+// progress, which would otherwise put back the not-ready state it began in.
+func markReady() {
+	resourcesMu.Lock()
+	defer resourcesMu.Unlock()
+	cacheReady.Store(true)
+}
+
+func loadResources(cfg Config) {
+	gary := resourceUpdate{res: garyResource, dir: cfg.GaryDir, baseURL: cfg.GaryURL, defaultImage: cfg.GaryDefaultImage}
+	goober := resourceUpdate{res: gooberResource, dir: cfg.GooberDir, baseURL: cfg.GooberURL, defaultImage: cfg.GooberDefaultImage}
+	var quotes, jokes []string
+
+	if cfg.ManifestURL != "" {
+		manifest, err := fetchManifest(cfg.ManifestURL)
+		if err != nil {
+			slog.Warn("falling back to local directories", "error", err)
+		} else {
+			gary.images = manifest.Gary
+			goober.images = manifest.Goober
+			quotes, jokes = manifest.Quotes, manifest.Jokes
+		}
+	}
+	swapResources(gary, goober)
+	quoteLines.useManifest(quotes)
+	jokeLines.useManifest(jokes)
+}
+
+func (res *imageResource) apply(dir, baseURL, defaultImage string, scan imageScan) {
+	if res.watcher != nil && dir != res.dir {
+		for _, old := range imageDirs(res.dir) {
+			_ = res.watcher.Remove(old)
+		}
+		watchDirs(res.watcher, dir)
+	}
+	res.dir = dir
+	res.baseURL = baseURL
+	res.defaultImage = defaultImage
+	res.setImages(scan)
+}
+
+func (res *imageResource) setImages(scan imageScan) {
+	imageSet := make(map[string]struct{}, len(scan.names))
+	for _, name := range scan.names {
+		imageSet[name] = struct{}{}
+	}
+	res.images = scan.names
+	res.imageSet = imageSet
+	res.latest = scan.latest
+	res.sizes = scan.sizes
+	res.files = scan.files
+	res.bag = nil
+	res.generation.Add(1)
+}
+
+// refresh rescans the resource's directories. A list that came from the
+// manifest is left alone, since the directories aren't its source.
+func (res *imageResource) refresh() {
+	imageCacheMu.RLock()
+	dir, fromManifest := res.dir, res.fromManifest
+	imageCacheMu.RUnlock()
+	if fromManifest {
+		return
+	}
+	scan := store.scan(dir, res.lastScan(dir))
+
+	imageCacheMu.Lock()
+	defer imageCacheMu.Unlock()
+	if res.dir == dir && !res.fromManifest {
+		res.setImages(scan)
+	}
+}
+
+// lastScan returns the file details and dimensions from the last scan of
+// dir, or nothing when the resource has since moved to another directory.
+func (res *imageResource) lastScan(dir string) imageScan {
+	imageCacheMu.RLock()
+	defer imageCacheMu.RUnlock()
+	if res.dir != dir {
+		return imageScan{}
+	}
+	return imageScan{sizes: res.sizes, files: res.files}
+}
+
+func (res *imageResource) directory() string {
+	imageCacheMu.RLock()
+	defer imageCacheMu.RUnlock()
+	return res.dir
+}
+
+func (res *imageResource) url() string {
+	imageCacheMu.RLock()
+	defer imageCacheMu.RUnlock()
+	return res.baseURL
+}
+
+// latestImage returns the most recently modified image, or "" when the
+// list came from a manifest and modification times are unknown.
+func (res *imageResource) latestImage() string {
+	imageCacheMu.RLock()
+	defer imageCacheMu.RUnlock()
+	return res.latest
+}
+
+type imageListing struct {
+	Name     string     `json:"name"`
+	URL      string     `json:"url"`
+	Bytes    int64      `json:"size,omitempty"`
+	Modified *time.Time `json:"modified,omitempty"`
+}
+
+// listImages snapshots every image with its URL and, when the list came
+// from a directory scan, its size and modification time.
+func (res *imageResource) listImages() []imageListing {
+	imageCacheMu.RLock()
+	defer imageCacheMu.RUnlock()
+	listings := make([]imageListing, len(res.images))
+	for i, name := range res.images {
+		listings[i] = imageListing{Name: name, URL: imageURL(res.baseURL, name)}
+		if info, ok := res.files[name]; ok {
+			modTime := info.modTime
+			listings[i].Bytes = info.bytes
+			listings[i].Modified = &modTime
+		}
+	}
+	return listings
+}
+
+// subscribe registers a channel that receives the name of each image added
+// to the resource. The returned func unregisters it.
+func (res *imageResource) subscribe() (<-chan string, func()) {
+	ch := make(chan string, 16)
+	res.streamMu.Lock()
+	if res.streams == nil {
+		res.streams = make(map[chan string]struct{})
+	}
+	res.streams[ch] = struct{}{}
+	res.streamMu.Unlock()
+
+	return ch, func() {
+		res.streamMu.Lock()
+		delete(res.streams, ch)
+		res.streamMu.Unlock()
+	}
+}
+
+// publish announces a new image to every subscriber, dropping it for any
+// that has fallen too far behind rather than blocking the watcher.
+func (res *imageResource) publish(name string) {
+	res.streamMu.Lock()
+	defer res.streamMu.Unlock()
+	for ch := range res.streams {
+		select {
+		case ch <- name:
+		default:
+		}
+	}
+}
+
+// size reports an image's dimensions if they were read when it was listed.
+func (res *imageResource) size(name string) (imageSize, bool) {
+	imageCacheMu.RLock()
+	defer imageCacheMu.RUnlock()
+	size, ok := res.sizes[name]
+	return size, ok
+}
+
+func (res *imageResource) randomImage() string {
+	imageCacheMu.RLock()
+	defer imageCacheMu.RUnlock()
+	if currentConfig().ShuffleBag && len(res.images) > 0 {
+		return res.images[res.nextFromBag()]
+	}
+	return getRandomFileName(res.images, res.defaultImage)
+}
+
+// nextFromBag pops the next index from a shuffled queue of every image,
+// reshuffling once it runs dry so each file is shown once per cycle.
+// Callers must hold imageCacheMu for reading.
+func (res *imageResource) nextFromBag() int {
+	res.bagMu.Lock()
+	defer res.bagMu.Unlock()
+	if len(res.bag) == 0 {
+		res.bag = rng.Perm(len(res.images))
+	}
+	next := res.bag[len(res.bag)-1]
+	res.bag = res.bag[:len(res.bag)-1]
+	return next
+}
+
+// randomImages picks up to n distinct images, sampling without replacement,
+// so the result is capped at the number of available files. When ext is set
+// only images with that extension are candidates; the default image stands
+// in for an empty resource, but a filter that matches nothing picks nothing.
+func (res *imageResource) randomImages(n int, ext string) []string {
+	imageCacheMu.RLock()
+	defer imageCacheMu.RUnlock()
+	candidates := res.images
+	if ext != "" {
+		candidates = res.withExt(ext)
+	} else if len(candidates) == 0 {
+		return []string{res.defaultImage}
+	}
+
+	n = min(n, len(candidates))
+	names := make([]string, n)
+	for i, index := range rng.Perm(len(candidates))[:n] {
+		names[i] = candidates[index]
+	}
+	return names
+}
+
+func (res *imageResource) randomImageWithExt(ext string) (string, bool) {
+	imageCacheMu.RLock()
+	defer imageCacheMu.RUnlock()
+	matches := res.withExt(ext)
+	if len(matches) == 0 {
+		return "", false
+	}
+	return matches[rng.Intn(len(matches))], true
+}
+
+// withExt lists the images with extension ext. The caller holds
+// imageCacheMu.
+func (res *imageResource) withExt(ext string) []string {
+	var matches []string
+	for _, image := range res.images {
+		if strings.EqualFold(strings.TrimPrefix(filepath.Ext(image), "."), ext) {
+			matches = append(matches, image)
+		}
+	}
+	return matches
+}
+
+func (res *imageResource) count() int {
+	imageCacheMu.RLock()
+	defer imageCacheMu.RUnlock()
+	return len(res.images)
+}
+
+func readLinesFromFile(filePath string) ([]string, error) {
+	fileContent, err := os.ReadFile(filePath)
+	if err != nil {
+		return nil, fmt.Errorf("could not read file %s: %w", filePath, err)
+	}
+
+	var lines []string
+	switch strings.ToLower(filepath.Ext(filePath)) {
+	case ".txt":
+		lines = parsePlainTextLines(fileContent)
+	case ".jsonl":
+		if lines, err = parseJSONLines(fileContent, filePath); err != nil {
+			return nil, err
+		}
+	default:
+		if err = json.Unmarshal(fileContent, &lines); err != nil {
+			return nil, fmt.Errorf("could not unmarshal JSON from %s: %w", filePath, err)
+		}
+	}
+
+	if !utf8.Valid(fileContent) {
+		lines = cleanInvalidUTF8(lines, filePath)
+	}
+	return lines, nil
+}
+
+var warnedInvalidUTF8 sync.Map
+
+// cleanInvalidUTF8 handles the entries of a file that isn't valid UTF-8,
+// following TEXT_ENCODING: "lossy" keeps them with U+FFFD in place of the
+// bad bytes, "strict" drops them. JSON decoding has already substituted
+// U+FFFD, so a replacement character also marks an entry as bad. Each file
+// is reported once rather than on every read.
+func cleanInvalidUTF8(lines []string, filePath string) []string {
+	strict := currentConfig().TextEncoding == "strict"
+	kept := make([]string, 0, len(lines))
+	var bad []int
+	for i, line := range lines {
+		if utf8.ValidString(line) && !strings.ContainsRune(line, utf8.RuneError) {
+			kept = append(kept, line)
+			continue
+		}
+		bad = append(bad, i)
+		if !strict {
+			kept = append(kept, strings.ToValidUTF8(line, "\uFFFD"))
+		}
+	}
+
+	if _, warned := warnedInvalidUTF8.LoadOrStore(filePath, struct{}{}); !warned {
+		slog.Warn("text file has entries with invalid UTF-8", "path", filePath, "entries", bad, "dropped", strict)
+	}
+	return kept
+}
+
+func parsePlainTextLines(content []byte) []string {
+	var lines []string
+	for _, line := range strings.Split(string(content), "\n") {
+		if line = strings.TrimSpace(line); line != "" {
+			lines = append(lines, line)
+		}
+	}
+	return lines
+}
+
+func parseJSONLines(content []byte, filePath string) ([]string, error) {
+	var lines []string
+	for i, raw := range strings.Split(string(content), "\n") {
+		raw = strings.TrimSpace(raw)
+		if raw == "" {
+			continue
+		}
+		var entry struct {
+			Text string `json:"text"`
+		}
+		if err := json.Unmarshal([]byte(raw), &entry); err != nil {
+			return nil, fmt.Errorf("could not unmarshal JSON on line %d of %s: %w", i+1, filePath, err)
+		}
+		if entry.Text != "" {
+			lines = append(lines, entry.Text)
+		}
+	}
+	return lines, nil
+}
+
+// checkTextFile loads a configured quotes or jokes file once so a missing
+// or malformed file is reported up front rather than on the first request.
+// Unusable files count as zero lines; lines from the manifest are counted
+// as they are.
+func checkTextFile(key string, text *textLines) int {
+	if lines := text.manifest.Load(); lines != nil {
+		return len(*lines)
+	}
+	filePath := text.path(currentConfig())
+	if filePath == "" {
+		slog.Warn("text file not configured", "setting", key)
+		return 0
+	}
+	lines, err := readLinesFromFile(filePath)
+	if err != nil {
+		slog.Warn("text file unusable", "setting", key, "error", err)
+		return 0
+	}
+	if len(lines) == 0 {
+		slog.Info("text file is empty", "setting", key, "path", filePath)
+	}
+	return len(lines)
+}
+
+// textLines is where the quotes or the jokes come from: the manifest when
+// it listed them, otherwise the configured file.
+type textLines struct {
+	path     func(*Config) string
+	manifest atomic.Pointer[[]string]
+}
+
+var (
+	quoteLines = &textLines{path: quotesFile}
+	jokeLines  = &textLines{path: jokesFile}
+)
+
+// useManifest serves lines in place of the file, or goes back to the file
+// when lines is nil.
+func (text *textLines) useManifest(lines []string) {
+	if lines == nil {
+		text.manifest.Store(nil)
+		return
+	}
+	text.manifest.Store(&lines)
+}
+
+func (text *textLines) lines() ([]string, error) {
+	if lines := text.manifest.Load(); lines != nil {
+		return *lines, nil
+	}
+	return readLinesFromFile(text.path(currentConfig()))
+}
+
+func (text *textLines) count() int {
+	lines, err := text.lines()
+	if err != nil {
+		return 0
+	}
+	return len(lines)
+}
+
+func (text *textLines) random() (string, error) {
+	lines, err := text.lines()
+	if err != nil {
+		return "", err
+	}
+
+	if len(lines) == 0 {
+		source := "the manifest"
+		if text.manifest.Load() == nil {
+			source = text.path(currentConfig())
+		}
+		return "", fmt.Errorf("%w in %s", errNoLines, source)
+	}
+	return lines[rng.Intn(len(lines))], nil
+}
+
+func extractNumberFromFilename(filename string) int {
+	re := regexp.MustCompile(`\d+`)
+	match := re.FindString(filename)
+	if match == "" {
+		return 0
+	}
+	var number int
+	fmt.Sscanf(match, "%d", &number)
+	return number
+}
+
+func (res *imageResource) has(name string) bool {
+	imageCacheMu.RLock()
+	defer imageCacheMu.RUnlock()
+	_, ok := res.imageSet[name]
+	return ok
+}
+
+func isRetryableReadError(err error) bool {
+	return errors.Is(err, syscall.EINTR) || errors.Is(err, syscall.EAGAIN)
+}
+
+func openWithRetry(path string, attempts int) (*os.File, error) {
+	for attempt := 1; ; attempt++ {
+		file, err := os.Open(path)
+		if err == nil || attempt >= attempts || !isRetryableReadError(err) {
+			return file, err
+		}
+		time.Sleep(time.Duration(attempt) * fileRetryBackoff)
+	}
+}
+
+type resizedImage struct {
+	data        []byte
+	contentType string
+	modTime     time.Time
+}
+
+// resizeCall is a resize in progress that concurrent requests for the same
+// key wait on instead of decoding the same file again.
+type resizeCall struct {
+	done    chan struct{}
+	resized resizedImage
+	err     error
+}
+
+// resizedLRU holds recent resizes up to maxBytes of image data, dropping
+// the least recently used first.
+type resizedLRU struct {
+	maxBytes int
+	bytes    int
+	order    *list.List
+	entries  map[string]*list.Element
+}
+
+type resizedEntry struct {
+	key     string
+	resized resizedImage
+}
+
+func newResizedLRU(maxBytes int) *resizedLRU {
+	return &resizedLRU{maxBytes: maxBytes, order: list.New(), entries: make(map[string]*list.Element)}
+}
+
+func (lru *resizedLRU) get(key string) (resizedImage, bool) {
+	element, ok := lru.entries[key]
+	if !ok {
+		return resizedImage{}, false
+	}
+	lru.order.MoveToFront(element)
+	return element.Value.(*resizedEntry).resized, true
+}
+
+func (lru *resizedLRU) add(key string, resized resizedImage) {
+	if len(resized.data) > lru.maxBytes {
+		return
+	}
+	if element, ok := lru.entries[key]; ok {
+		lru.remove(element)
+	}
+	lru.entries[key] = lru.order.PushFront(&resizedEntry{key: key, resized: resized})
+	lru.bytes += len(resized.data)
+	for lru.bytes > lru.maxBytes {
+		lru.remove(lru.order.Back())
+	}
+}
+
+func (lru *resizedLRU) remove(element *list.Element) {
+	entry := lru.order.Remove(element).(*resizedEntry)
+	delete(lru.entries, entry.key)
+	lru.bytes -= len(entry.resized.data)
+}
+
 var (
-	garyImages   []string
-	gooberImages []string
-	imageCacheMu sync.RWMutex
+	resizedMu      sync.Mutex
+	resizedCache   = newResizedLRU(maxResizedBytes)
+	resizeInFlight = make(map[string]*resizeCall)
 )
 
-func cacheFileNames(dirPath string) []string {
-	files, err := os.ReadDir(dirPath)
-	if err != nil {
-		fmt.Printf("Error reading dir %s: %v\n", dirPath, err)
-		return nil
+// imageWorkers bounds how many decodes and re-encodes run at once, so CPU
+// bound image work can't starve the goroutines serving everything else.
+// newHandler sizes it from IMAGE_WORKERS.
+var imageWorkers atomic.Pointer[chan struct{}]
+
+// sniffedImageTypes maps the extensions whose content http.DetectContentType
+// can recognise to the type it should report.
+var sniffedImageTypes = map[string]string{
+	".jpg":  "image/jpeg",
+	".jpeg": "image/jpeg",
+	".png":  "image/png",
+	".gif":  "image/gif",
+	".webp": "image/webp",
+	".bmp":  "image/bmp",
+}
+
+// contentMatchesExtension sniffs the start of file and reports whether it
+// looks like the format its name claims, rewinding the file afterwards.
+// Extensions the sniffer can't judge are given the benefit of the doubt.
+func contentMatchesExtension(file io.ReadSeeker, name string) bool {
+	expected, ok := sniffedImageTypes[strings.ToLower(filepath.Ext(name))]
+	if !ok {
+		return true
+	}
+	header := make([]byte, 512)
+	n, _ := io.ReadFull(file, header)
+	if _, err := file.Seek(0, io.SeekStart); err != nil {
+		return false
+	}
+	return http.DetectContentType(header[:n]) == expected
+}
+
+// resizeImage scales an image to width x height with nearest-neighbour
+// sampling. A zero dimension is derived from the other to keep the aspect
+// ratio. JPEGs stay JPEG; everything else is re-encoded as PNG. The header
+// is checked first, so an image claiming more than maxResizeSourcePixels is
+// refused before anything is allocated for it.
+func resizeImage(r io.ReadSeeker, width, height int) (resizedImage, error) {
+	config, _, err := image.DecodeConfig(r)
+	if err != nil {
+		return resizedImage{}, err
+	}
+	if int64(config.Width)*int64(config.Height) > maxResizeSourcePixels {
+		return resizedImage{}, errImageTooLarge
+	}
+	if _, err := r.Seek(0, io.SeekStart); err != nil {
+		return resizedImage{}, err
+	}
+
+	src, format, err := image.Decode(r)
+	if err != nil {
+		return resizedImage{}, err
+	}
+
+	bounds := src.Bounds()
+	if bounds.Empty() {
+		return resizedImage{}, errors.New("image has no pixels")
+	}
+	if width == 0 {
+		width = max(1, height*bounds.Dx()/bounds.Dy())
+	}
+	if height == 0 {
+		height = max(1, width*bounds.Dy()/bounds.Dx())
+	}
+	width, height = min(width, maxResizeDimension), min(height, maxResizeDimension)
+
+	dst := image.NewRGBA(image.Rect(0, 0, width, height))
+	for y := 0; y < height; y++ {
+		sy := bounds.Min.Y + y*bounds.Dy()/height
+		for x := 0; x < width; x++ {
+			dst.Set(x, y, src.At(bounds.Min.X+x*bounds.Dx()/width, sy))
+		}
+	}
+
+	var buf bytes.Buffer
+	if format == "jpeg" {
+		err = jpeg.Encode(&buf, dst, &jpeg.Options{Quality: 85})
+		return resizedImage{data: buf.Bytes(), contentType: "image/jpeg"}, err
+	}
+	err = png.Encode(&buf, dst)
+	return resizedImage{data: buf.Bytes(), contentType: "image/png"}, err
+}
+
+// resizeOnce returns the cached resize for key if it is still current, and
+// otherwise runs resize, sharing one run among concurrent callers. A caller
+// stops waiting on someone else's run once ctx is done.
+func resizeOnce(ctx context.Context, key string, modTime time.Time, resize func() (resizedImage, error)) (resizedImage, error) {
+	resizedMu.Lock()
+	if resized, ok := resizedCache.get(key); ok && resized.modTime.Equal(modTime) {
+		resizedMu.Unlock()
+		return resized, nil
+	}
+	if call, ok := resizeInFlight[key]; ok {
+		resizedMu.Unlock()
+		select {
+		case <-call.done:
+			return call.resized, call.err
+		case <-ctx.Done():
+			return resizedImage{}, ctx.Err()
+		}
+	}
+	call := &resizeCall{done: make(chan struct{}), err: errors.New("resize did not complete")}
+	resizeInFlight[key] = call
+	resizedMu.Unlock()
+
+	// Waiters are released even if resize panics, seeing the default error.
+	defer func() {
+		resizedMu.Lock()
+		delete(resizeInFlight, key)
+		if call.err == nil {
+			resizedCache.add(key, call.resized)
+		}
+		resizedMu.Unlock()
+		close(call.done)
+	}()
+
+	resized, err := resize()
+	resized.modTime = modTime
+	call.resized, call.err = resized, err
+	return resized, err
+}
+
+// serveResizedImage serves a scaled copy of file, reusing an earlier result
+// for the same source and dimensions until the source file changes.
+func serveResizedImage(c *gin.Context, source string, info os.FileInfo, file io.ReadSeeker, width, height int) {
+	key := fmt.Sprintf("%s?w=%d&h=%d", source, width, height)
+	workers := *imageWorkers.Load()
+	ctx := c.Request.Context()
+	resized, err := resizeOnce(ctx, key, info.ModTime(), func() (resizedImage, error) {
+		select {
+		case workers <- struct{}{}:
+		case <-ctx.Done():
+			return resizedImage{}, ctx.Err()
+		}
+		defer func() { <-workers }()
+		return resizeImage(file, width, height)
+	})
+	if ctx.Err() != nil {
+		// Out of time waiting for a worker; requestTimeout has answered.
+		return
+	}
+	if errors.Is(err, context.DeadlineExceeded) || errors.Is(err, context.Canceled) {
+		// The request whose resize this shared ran out of time instead.
+		respondError(c, http.StatusServiceUnavailable, "timeout", "image processing timed out")
+		return
+	}
+	if errors.Is(err, errImageTooLarge) {
+		respondError(c, http.StatusRequestEntityTooLarge, "payload_too_large", err.Error())
+		return
+	}
+	if errors.Is(err, image.ErrFormat) {
+		respondError(c, http.StatusUnsupportedMediaType, "unsupported_media_type", "only PNG, JPEG and GIF images can be resized")
+		return
+	}
+	if err != nil {
+		respondError(c, http.StatusInternalServerError, "file_error", "could not resize image")
+		return
+	}
+
+	c.Header("Content-Type", resized.contentType)
+	http.ServeContent(c.Writer, c.Request, "", resized.modTime, bytes.NewReader(resized.data))
+}
+
+// serveBase64Image returns the image inline in JSON for clients that can't
+// fetch or render a binary body.
+func serveBase64Image(c *gin.Context, info os.FileInfo, file io.Reader) {
+	if info.Size() > maxBase64ImageBytes {
+		respondError(c, http.StatusRequestEntityTooLarge, "payload_too_large", "image is too large to inline as base64")
+		return
+	}
+	data, err := io.ReadAll(file)
+	if err != nil {
+		respondError(c, http.StatusInternalServerError, "file_error", "could not read image")
+		return
+	}
+
+	contentType := mime.TypeByExtension(filepath.Ext(info.Name()))
+	if contentType == "" {
+		contentType = http.DetectContentType(data)
+	}
+	respondJSON(c, http.StatusOK, gin.H{
+		"filename":     info.Name(),
+		"content_type": contentType,
+		"data":         base64.StdEncoding.EncodeToString(data),
+	})
+}
+
+func serveImageFile(c *gin.Context, res *imageResource, name string) {
+	location := res.directory()
+	defer func() {
+		if status := c.Writer.Status(); status >= 200 && status < 300 && c.Writer.Size() > 0 {
+			res.bytesServed.Add(uint64(c.Writer.Size()))
+		}
+	}()
+
+	width, err := queryPositiveInt(c, "w", 0, maxResizeDimension)
+	if err != nil {
+		respondError(c, http.StatusBadRequest, "bad_request", err.Error())
+		return
+	}
+	height, err := queryPositiveInt(c, "h", 0, maxResizeDimension)
+	if err != nil {
+		respondError(c, http.StatusBadRequest, "bad_request", err.Error())
+		return
+	}
+
+	cleanName := strings.TrimPrefix(path.Clean("/"+name), "/")
+	file, err := store.open(location, cleanName)
+	if err != nil && !store.available(location) {
+		respondError(c, http.StatusServiceUnavailable, "storage_unavailable", "image storage is unavailable")
+		return
+	}
+	if errors.Is(err, fs.ErrNotExist) {
+		respondError(c, http.StatusNotFound, "not_found", "image not found")
+		return
+	}
+	if err != nil {
+		respondError(c, http.StatusInternalServerError, "file_error", "could not read image")
+		return
+	}
+	defer file.Close()
+
+	info, err := file.Stat()
+	if err != nil || info.IsDir() {
+		respondError(c, http.StatusNotFound, "not_found", "image not found")
+		return
+	}
+
+	if info.Size() == 0 {
+		slog.Warn("refusing to serve empty image file", "location", location, "file", cleanName)
+		respondError(c, http.StatusNotFound, "not_found", "image not found")
+		return
+	}
+	if currentConfig().ValidateImageContent && !contentMatchesExtension(file, info.Name()) {
+		slog.Warn("image content does not match its extension", "location", location, "file", cleanName)
+		respondError(c, http.StatusNotFound, "not_found", "image not found")
+		return
+	}
+
+	if maxBytes := currentConfig().MaxImageBytes; maxBytes > 0 && info.Size() > int64(maxBytes) {
+		respondError(c, http.StatusRequestEntityTooLarge, "payload_too_large", "image exceeds the maximum response size")
+		return
+	}
+
+	if queryBool(c, "download") {
+		if disposition := mime.FormatMediaType("attachment", map[string]string{"filename": info.Name()}); disposition != "" {
+			c.Header("Content-Disposition", disposition)
+		}
+	}
+	if c.Query("encode") == "base64" {
+		serveBase64Image(c, info, file)
+		return
+	}
+	if width > 0 || height > 0 {
+		serveResizedImage(c, location+"/"+cleanName, info, file, width, height)
+		return
+	}
+	http.ServeContent(c.Writer, c.Request, info.Name(), info.ModTime(), file)
+}
+
+func serveImageFileHandler(res *imageResource) gin.HandlerFunc {
+	return func(c *gin.Context) {
+		serveImageFile(c, res, c.Param("filepath"))
+	}
+}
+
+// respondEmptyResource answers for a resource with no images as
+// EMPTY_RESOURCE_BEHAVIOR says, reporting false when the caller should go
+// ahead and fall back to the default image.
+func respondEmptyResource(c *gin.Context, res *imageResource) bool {
+	if res.count() > 0 {
+		return false
+	}
+	switch currentConfig().EmptyResourceBehavior {
+	case "not_found":
+		respondError(c, http.StatusNotFound, "not_found", "no "+res.label+" images available")
+		return true
+	case "error":
+		respondError(c, http.StatusServiceUnavailable, "no_images", "no "+res.label+" images are loaded")
+		return true
+	}
+	return false
+}
+
+func serveRandomImageHandler(res *imageResource) gin.HandlerFunc {
+	return func(c *gin.Context) {
+		requested := strings.TrimPrefix(c.Param("path"), "/")
+		if requested != "" && res.has(requested) {
+			setCacheControl(c, "public, max-age=86400")
+			serveImageFile(c, res, requested)
+			return
+		}
+
+		if requested == "latest" {
+			latest := res.latestImage()
+			if latest == "" {
+				respondError(c, http.StatusNotFound, "not_found", "no latest "+res.label+" image known")
+				return
+			}
+			setCacheControl(c, "no-cache")
+			serveImageFile(c, res, latest)
+			return
+		}
+
+		if format := queryExt(c, "format"); format != "" {
+			serveImageByExtensionHandler(res, format)(c)
+			return
+		}
+
+		if respondEmptyResource(c, res) {
+			return
+		}
+		setCacheControl(c, "no-store")
+		imageName := res.randomImage()
+		if currentConfig().ImageRedirect {
+			c.Redirect(http.StatusFound, imageURL(res.url(), imageName))
+			return
+		}
+		serveImageFile(c, res, imageName)
+	}
+}
+
+func newRequestID() string {
+	buf := make([]byte, 8)
+	if _, err := crand.Read(buf); err != nil {
+		return strconv.FormatInt(time.Now().UnixNano(), 36)
+	}
+	return hex.EncodeToString(buf)
+}
+
+func assignRequestID(c *gin.Context) {
+	id := c.GetHeader("X-Request-ID")
+	if !requestIDPattern.MatchString(id) {
+		id = newRequestID()
+	}
+	c.Set(requestIDKey, id)
+	c.Header("X-Request-ID", id)
+}
+
+func logRequest(c *gin.Context) {
+	start := time.Now()
+	path := c.Request.URL.Path
+	c.Next()
+
+	attrs := []any{
+		"status", c.Writer.Status(),
+		"method", c.Request.Method,
+		"path", path,
+		"latency", time.Since(start),
+		"client_ip", c.ClientIP(),
+		"request_id", c.GetString(requestIDKey),
+	}
+	if errs := c.Errors.ByType(gin.ErrorTypePrivate).String(); errs != "" {
+		attrs = append(attrs, "errors", errs)
+	}
+	slog.Info("request", attrs...)
+}
+
+// setOptionalHeader sets a configurable header unless it was turned off.
+func setOptionalHeader(c *gin.Context, key, value string) {
+	if value != "off" {
+		c.Header(key, value)
+	}
+}
+
+// respondPanic answers a request whose handler panicked with the usual
+// JSON error; gin has already logged the panic and its stack.
+func respondPanic(c *gin.Context, _ any) {
+	respondError(c, http.StatusInternalServerError, "internal_error", "internal server error")
+}
+
+// securityHeaders adds the hardening headers every response carries.
+func securityHeaders(c *gin.Context) {
+	cfg := currentConfig()
+	c.Header("X-Content-Type-Options", "nosniff")
+	setOptionalHeader(c, "X-Frame-Options", cfg.FrameOptions)
+	setOptionalHeader(c, "Referrer-Policy", cfg.ReferrerPolicy)
+}
+
+// limitRequestBody refuses bodies declared larger than MAX_REQUEST_BODY_BYTES
+// and caps the rest, so a handler reading one fails instead of buffering
+// an unbounded upload.
+func limitRequestBody(c *gin.Context) {
+	limit := int64(currentConfig().MaxRequestBodyBytes)
+	if limit <= 0 {
+		return
+	}
+	if c.Request.ContentLength > limit {
+		respondError(c, http.StatusRequestEntityTooLarge, "payload_too_large", "request body too large")
+		return
+	}
+	c.Request.Body = http.MaxBytesReader(c.Writer, c.Request.Body, limit)
+}
+
+// applyCacheControl sets the Cache-Control configured for the matched route
+// kind, which then takes precedence over the handler's own default.
+func applyCacheControl(c *gin.Context) {
+	if value, ok := currentConfig().CacheControl[routeKind(c.FullPath())]; ok {
+		c.Header("Cache-Control", value)
+	}
+}
+
+// setCacheControl sets a handler's default Cache-Control unless a
+// configured override is already in place.
+func setCacheControl(c *gin.Context, value string) {
+	if c.Writer.Header().Get("Cache-Control") == "" {
+		c.Header("Cache-Control", value)
+	}
+}
+
+func requireAPIKey(c *gin.Context) {
+	keys := currentConfig().APIKeys
+	if len(keys) == 0 {
+		return
+	}
+	switch c.Request.URL.Path {
+	case "/health", "/healthz", "/ready":
+		return
+	}
+
+	provided := c.GetHeader("X-API-Key")
+	if provided == "" {
+		provided = c.Query("api_key")
+	}
+	valid := 0
+	for _, key := range keys {
+		valid |= subtle.ConstantTimeCompare([]byte(provided), []byte(key))
+	}
+	if valid != 1 {
+		respondError(c, http.StatusUnauthorized, "unauthorized", "missing or invalid API key")
 	}
+}
 
-	names := make([]string, 0, len(files))
-	for _, file := range files {
-		if !file.IsDir() {
-			names = append(names, file.Name())
+func serveImageByExtensionHandler(res *imageResource, ext string) gin.HandlerFunc {
+	return func(c *gin.Context) {
+		imageName, ok := res.randomImageWithExt(ext)
+		if !ok {
+			respondError(c, http.StatusNotFound, "not_found", fmt.Sprintf("no .%s images available", ext))
+			return
 		}
+		setCacheControl(c, "no-store")
+		serveImageFile(c, res, imageName)
 	}
-	return names
 }
 
-func getRandomFileName(images []string, defaultName string) string {
-	if len(images) == 0 {
-		return defaultName
-	}
-	return images[rand.Intn(len(images))]
+func respondError(c *gin.Context, status int, code, message string) {
+	c.AbortWithStatusJSON(status, gin.H{
+		"error": gin.H{
+			"code":    code,
+			"message": message,
+		},
+	})
 }
 
-func getRandomLineFromFile(filePath string) (string, error) {
-	fileContent, err := os.ReadFile(filePath)
-	if err != nil {
-		return "", fmt.Errorf("could not read file %s: %w", filePath, err)
+// respondJSON writes obj as JSON, or as JSONP when a ?callback= names a
+// plain dotted identifier. Anything else is refused so the callback can't
+// inject script.
+func respondJSON(c *gin.Context, status int, obj any) {
+	callback := c.Query("callback")
+	if callback == "" {
+		c.JSON(status, obj)
+		return
+	}
+	if !jsonpCallbackPattern.MatchString(callback) {
+		respondError(c, http.StatusBadRequest, "bad_request", "callback must be a JavaScript identifier")
+		return
 	}
+	c.JSONP(status, obj)
+}
 
-	var lines []string
-	err = json.Unmarshal(fileContent, &lines)
-	if err != nil {
-		return "", fmt.Errorf("could not unmarshal JSON from %s: %w", filePath, err)
+// respondJSONWithETag is respondJSON for responses that only change when a
+// resource is rescanned. The ETag combines the request URI with generation,
+// so a client polling with If-None-Match gets a 304 until the next reload.
+func respondJSONWithETag(c *gin.Context, generation uint64, obj any) {
+	h := fnv.New64a()
+	h.Write([]byte(c.Request.URL.RequestURI()))
+	etag := fmt.Sprintf(`"%x-%d"`, h.Sum64(), generation)
+	c.Header("ETag", etag)
+
+	for _, candidate := range strings.Split(c.GetHeader("If-None-Match"), ",") {
+		candidate = strings.TrimPrefix(strings.TrimSpace(candidate), "W/")
+		if candidate == etag || candidate == "*" {
+			c.Status(http.StatusNotModified)
+			return
+		}
 	}
+	respondJSON(c, http.StatusOK, obj)
+}
 
-	if len(lines) == 0 {
-		return "", fmt.Errorf("no lines found in %s", filePath)
+// queryPositiveInt reads a positive integer query parameter, returning def
+// when it is absent and capping the result at limit. Malformed, zero or
+// negative values are an error.
+func queryPositiveInt(c *gin.Context, key string, def, limit int) (int, error) {
+	raw, ok := c.GetQuery(key)
+	if !ok {
+		return def, nil
+	}
+	value, err := strconv.Atoi(raw)
+	if err != nil || value < 1 {
+		return 0, fmt.Errorf("%s must be a positive integer", key)
 	}
-	return lines[rand.Intn(len(lines))], nil
+	return min(value, limit), nil
 }
 
-func extractNumberFromFilename(filename string) int {
-	re := regexp.MustCompile(`\d+`)
-	match := re.FindString(filename)
-	if match == "" {
-		return 0
+// queryBool reports whether a query parameter is set to a true value such
+// as 1 or true; absent and malformed values count as false.
+func queryBool(c *gin.Context, key string) bool {
+	value, _ := strconv.ParseBool(c.Query(key))
+	return value
+}
+
+// queryExt reads a file extension query parameter, accepting it with or
+// without the leading dot.
+func queryExt(c *gin.Context, key string) string {
+	return strings.TrimPrefix(c.Query(key), ".")
+}
+
+func imageURL(baseURL, imageName string) string {
+	return strings.TrimRight(baseURL, "/") + "/" + strings.TrimLeft(imageName, "/")
+}
+
+func serveImageURLHandler(res *imageResource) gin.HandlerFunc {
+	return func(c *gin.Context) {
+		setCacheControl(c, "no-store")
+		count, err := queryPositiveInt(c, "count", 0, maxImageBatch)
+		if err != nil {
+			respondError(c, http.StatusBadRequest, "bad_request", err.Error())
+			return
+		}
+		if respondEmptyResource(c, res) {
+			return
+		}
+		ext := queryExt(c, "ext")
+		if count > 0 {
+			names := res.randomImages(count, ext)
+			if len(names) == 0 {
+				respondError(c, http.StatusNotFound, "not_found", fmt.Sprintf("no .%s images available", ext))
+				return
+			}
+			baseURL := res.url()
+			urls := make([]string, len(names))
+			for i, name := range names {
+				urls[i] = imageURL(baseURL, name)
+			}
+			respondJSON(c, http.StatusOK, gin.H{"urls": urls})
+			return
+		}
+
+		var imageName string
+		if ext != "" {
+			var ok bool
+			if imageName, ok = res.randomImageWithExt(ext); !ok {
+				respondError(c, http.StatusNotFound, "not_found", fmt.Sprintf("no .%s images available", ext))
+				return
+			}
+		} else {
+			imageName = res.randomImage()
+		}
+		number := extractNumberFromFilename(imageName)
+		url := imageURL(res.url(), imageName)
+
+		body := gin.H{
+			"url":    url,
+			"number": number,
+		}
+		if size, ok := res.size(imageName); ok {
+			body["width"] = size.Width
+			body["height"] = size.Height
+		}
+		respondJSON(c, http.StatusOK, body)
 	}
-	var number int
-	fmt.Sscanf(match, "%d", &number)
-	return number
 }
 
-func serveRandomImageHandler(images *[]string, defaultImage, imageDir string) gin.HandlerFunc {
+func quotesFile(cfg *Config) string {
+	return cfg.QuotesFile
+}
+
+func jokesFile(cfg *Config) string {
+	return cfg.JokesFile
+}
+
+func serveRandomLineHandler(key string, text *textLines) gin.HandlerFunc {
 	return func(c *gin.Context) {
-		c.Header("Cache-Control", "no-store")
-		imageCacheMu.RLock()
-		imageName := getRandomFileName(*images, defaultImage)
-		imageCacheMu.RUnlock()
-		c.File(filepath.Join(imageDir, imageName))
+		line, err := text.random()
+		if errors.Is(err, errNoLines) {
+			respondError(c, http.StatusNotFound, "not_found", err.Error())
+			return
+		}
+		if err != nil {
+			respondError(c, http.StatusInternalServerError, "file_error", err.Error())
+			return
+		}
+
+		respondJSON(c, http.StatusOK, gin.H{key: line})
 	}
 }
 
-func serveImageURLHandler(baseURL, imageDir string, images *[]string, defaultImage string) gin.HandlerFunc {
+func serveLineAtIndexHandler(key string, text *textLines) gin.HandlerFunc {
 	return func(c *gin.Context) {
-		imageCacheMu.RLock()
-		imageName := getRandomFileName(*images, defaultImage)
-		imageCacheMu.RUnlock()
+		index, err := strconv.Atoi(c.Param("index"))
+		if err != nil || index < 0 {
+			respondError(c, http.StatusBadRequest, "bad_request", "index must be a non-negative integer")
+			return
+		}
 
-		number := extractNumberFromFilename(imageName)
+		lines, err := text.lines()
+		if err != nil {
+			respondError(c, http.StatusInternalServerError, "file_error", err.Error())
+			return
+		}
+		if index >= len(lines) {
+			respondError(c, http.StatusNotFound, "not_found", fmt.Sprintf("no %s at index %d", key, index))
+			return
+		}
+
+		respondJSON(c, http.StatusOK, gin.H{key: lines[index], "index": index})
+	}
+}
 
-		cleanBaseURL := baseURL
-		if len(cleanBaseURL) > 0 && cleanBaseURL[len(cleanBaseURL)-1] == '/' {
-			cleanBaseURL = cleanBaseURL[:len(cleanBaseURL)-1]
+var imageListOrders = map[string]func(a, b imageListing) bool{
+	"name": func(a, b imageListing) bool { return a.Name < b.Name },
+	"size": func(a, b imageListing) bool { return a.Bytes < b.Bytes },
+	"mtime": func(a, b imageListing) bool {
+		return a.Modified != nil && (b.Modified == nil || a.Modified.Before(*b.Modified))
+	},
+}
+
+// serveImageListHandler lists every image of a resource, sorted by
+// ?sort=name|mtime|size (default name) in ?order=asc|desc (default asc).
+func serveImageListHandler(res *imageResource) gin.HandlerFunc {
+	return func(c *gin.Context) {
+		less, ok := imageListOrders[c.DefaultQuery("sort", "name")]
+		if !ok {
+			respondError(c, http.StatusBadRequest, "bad_request", "sort must be name, mtime or size")
+			return
+		}
+		order := c.DefaultQuery("order", "asc")
+		if order != "asc" && order != "desc" {
+			respondError(c, http.StatusBadRequest, "bad_request", "order must be asc or desc")
+			return
 		}
-		url := fmt.Sprintf("%s/%s", cleanBaseURL, imageName)
 
-		c.JSON(http.StatusOK, gin.H{
-			"url":    url,
-			"number": number,
+		generation := res.generation.Load()
+		listings := res.listImages()
+		sort.SliceStable(listings, func(i, j int) bool {
+			if order == "desc" {
+				return less(listings[j], listings[i])
+			}
+			return less(listings[i], listings[j])
 		})
+		respondJSONWithETag(c, generation, gin.H{"images": listings, "count": len(listings)})
 	}
 }
 
-func serveRandomLineHandler(filePath string) gin.HandlerFunc {
+// serveImageStreamHandler keeps a server-sent events stream open and emits
+// an "image" event with the name and URL of each image added to res.
+func serveImageStreamHandler(res *imageResource) gin.HandlerFunc {
 	return func(c *gin.Context) {
-		line, err := getRandomLineFromFile(filePath)
-		if err != nil {
-			c.JSON(http.StatusInternalServerError, gin.H{"error": err.Error()})
-			return
+		images, unsubscribe := res.subscribe()
+		defer unsubscribe()
+
+		c.Header("Content-Type", "text/event-stream")
+		setCacheControl(c, "no-cache")
+		c.Header("X-Accel-Buffering", "no")
+		c.Status(http.StatusOK)
+		c.Writer.Flush()
+
+		heartbeat := time.NewTicker(streamHeartbeat)
+		defer heartbeat.Stop()
+		for {
+			select {
+			case name := <-images:
+				payload, _ := json.Marshal(gin.H{"name": name, "url": imageURL(res.url(), name)})
+				fmt.Fprintf(c.Writer, "event: image\ndata: %s\n\n", payload)
+			case <-heartbeat.C:
+				fmt.Fprint(c.Writer, ": ping\n\n")
+			case <-c.Request.Context().Done():
+				return
+			case <-streamsClosing:
+				return
+			}
+			c.Writer.Flush()
 		}
+	}
+}
 
-		var key string
-		switch filepath.Base(filePath) {
-		case filepath.Base(os.Getenv("QUOTES_FILE")):
-			key = "quote"
-		case filepath.Base(os.Getenv("JOKES_FILE")):
-			key = "joke"
-		default:
-			key = "line"
+// isStreamPath reports whether path is an event stream, which is exempt from
+// the request timeout because it never finishes on its own.
+func isStreamPath(path string) bool {
+	path = normalizeResourcePath(path)
+	return path == "/gary/stream" || path == "/goober/stream"
+}
+
+// serveCardHandler pairs a random quote with a random Gary image URL,
+// leaving out whichever of the two has nothing to offer.
+func serveCardHandler(c *gin.Context) {
+	setCacheControl(c, "no-store")
+	card := gin.H{}
+	if quote, err := quoteLines.random(); err == nil {
+		card["quote"] = quote
+	}
+	if garyResource.count() > 0 {
+		card["image"] = imageURL(garyResource.url(), garyResource.randomImage())
+	}
+	respondJSON(c, http.StatusOK, card)
+}
+
+// hasAdminToken reports whether the request carries ADMIN_TOKEN as a bearer
+// token, answering 403 itself when it doesn't.
+func hasAdminToken(c *gin.Context) bool {
+	adminToken := currentConfig().AdminToken
+	token, found := strings.CutPrefix(c.GetHeader("Authorization"), "Bearer ")
+	if !found || adminToken == "" || subtle.ConstantTimeCompare([]byte(token), []byte(adminToken)) != 1 {
+		respondError(c, http.StatusForbidden, "forbidden", "missing or invalid admin token")
+		return false
+	}
+	return true
+}
+
+// serveAdminReloadHandler rescans the image sources on demand, for hosts
+// where the directory watcher can't receive filesystem events.
+func serveAdminReloadHandler(c *gin.Context) {
+	if !hasAdminToken(c) {
+		return
+	}
+	cfg := *currentConfig()
+
+	reloadResources(cfg)
+	slog.Info("reloaded image lists on request", "gary", garyResource.count(), "goober", gooberResource.count())
+	c.JSON(http.StatusOK, gin.H{
+		"gary":   garyResource.count(),
+		"goober": gooberResource.count(),
+		"quotes": quoteLines.count(),
+		"jokes":  jokeLines.count(),
+	})
+}
+
+const redacted = "[redacted]"
+
+// redactConfig returns a copy of cfg that is safe to show, with credentials
+// masked.
+func redactConfig(cfg Config) Config {
+	if cfg.AdminToken != "" {
+		cfg.AdminToken = redacted
+	}
+	if len(cfg.APIKeys) > 0 {
+		keys := make([]string, len(cfg.APIKeys))
+		for i := range keys {
+			keys[i] = redacted
 		}
+		cfg.APIKeys = keys
+	}
+	return cfg
+}
 
-		c.JSON(http.StatusOK, gin.H{key: line})
+// serveDebugConfigHandler returns the configuration in effect, after env,
+// .env and defaults are merged, with credentials masked.
+func serveDebugConfigHandler(c *gin.Context) {
+	if !hasAdminToken(c) {
+		return
 	}
+	setCacheControl(c, "no-store")
+	c.JSON(http.StatusOK, redactConfig(*currentConfig()))
 }
 
-func startDirectoryWatcher(dir string, cache *[]string, label string) {
-	watcher, err := fsnotify.NewWatcher()
+func serveStatsHandler(c *gin.Context) {
+	data := gin.H{
+		"Uptime":            metrics.uptime().Truncate(time.Second).String(),
+		"Requests":          metrics.requestCount(),
+		"RPS":               metrics.requestsPerSecond(),
+		"AvgLatency":        metrics.averageLatency().String(),
+		"P50":               metrics.responseTimeP50().String(),
+		"P95":               metrics.responseTimeP95().String(),
+		"P99":               metrics.responseTimeP99().String(),
+		"Routes":            metrics.routeCounts(),
+		"Statuses":          metrics.statusCounts(),
+		"ActiveConnections": metrics.activeConnections.Load(),
+		"BytesSent":         metrics.bytesSent.Load(),
+		"CompressionRatio":  metrics.compressionRatio(),
+		"GaryCount":         garyResource.count(),
+		"GooberCount":       gooberResource.count(),
+		"QuoteCount":        quoteLines.count(),
+		"JokeCount":         jokeLines.count(),
+	}
+
+	setCacheControl(c, "no-store")
+	c.Header("Content-Type", "text/html; charset=utf-8")
+	c.Status(http.StatusOK)
+	if err := statsTemplate.Execute(c.Writer, data); err != nil {
+		slog.Error("failed to render stats page", "error", err)
+	}
+}
+
+func serveMetricsHandler(c *gin.Context) {
+	setCacheControl(c, "no-store")
+	c.Header("Vary", "Accept")
+	if strings.Contains(c.GetHeader("Accept"), "application/json") {
+		serveMetricsJSON(c)
+		return
+	}
+
+	var b strings.Builder
+	b.WriteString("# HELP garyapi_requests_total Requests handled, by route.\n")
+	b.WriteString("# TYPE garyapi_requests_total counter\n")
+	for _, rc := range metrics.routeCounts() {
+		fmt.Fprintf(&b, "garyapi_requests_total{route=%q} %d\n", rc.Label, rc.Count)
+	}
+	b.WriteString("# HELP garyapi_responses_total Responses sent, by status class.\n")
+	b.WriteString("# TYPE garyapi_responses_total counter\n")
+	for _, sc := range metrics.statusCounts() {
+		fmt.Fprintf(&b, "garyapi_responses_total{status=%q} %d\n", sc.Label, sc.Count)
+	}
+	b.WriteString("# HELP garyapi_request_timeouts_total Requests that ran past REQUEST_TIMEOUT.\n")
+	b.WriteString("# TYPE garyapi_request_timeouts_total counter\n")
+	fmt.Fprintf(&b, "garyapi_request_timeouts_total %d\n", metrics.timeouts.Load())
+	b.WriteString("# HELP garyapi_response_time_seconds Response time quantiles.\n")
+	b.WriteString("# TYPE garyapi_response_time_seconds gauge\n")
+	fmt.Fprintf(&b, "garyapi_response_time_seconds{quantile=\"0.5\"} %g\n", metrics.responseTimeP50().Seconds())
+	fmt.Fprintf(&b, "garyapi_response_time_seconds{quantile=\"0.95\"} %g\n", metrics.responseTimeP95().Seconds())
+	fmt.Fprintf(&b, "garyapi_response_time_seconds{quantile=\"0.99\"} %g\n", metrics.responseTimeP99().Seconds())
+	b.WriteString("# HELP garyapi_active_connections Open client connections.\n")
+	b.WriteString("# TYPE garyapi_active_connections gauge\n")
+	fmt.Fprintf(&b, "garyapi_active_connections %d\n", metrics.activeConnections.Load())
+	b.WriteString("# HELP garyapi_connections_total Accepted client connections, by remote address class.\n")
+	b.WriteString("# TYPE garyapi_connections_total counter\n")
+	for i, label := range addressClassLabels {
+		fmt.Fprintf(&b, "garyapi_connections_total{class=%q} %d\n", label, metrics.addressClasses[i].Load())
+	}
+	b.WriteString("# HELP garyapi_response_size_bytes Response body sizes.\n")
+	b.WriteString("# TYPE garyapi_response_size_bytes histogram\n")
+	var cumulative uint64
+	for i := range metrics.sizeBuckets {
+		cumulative += metrics.sizeBuckets[i].Load()
+		le := "+Inf"
+		if i < sizeBucketCount {
+			le = strconv.Itoa(sizeBucketBound(i))
+		}
+		fmt.Fprintf(&b, "garyapi_response_size_bytes_bucket{le=%q} %d\n", le, cumulative)
+	}
+	fmt.Fprintf(&b, "garyapi_response_size_bytes_sum %d\n", metrics.bytesSent.Load())
+	fmt.Fprintf(&b, "garyapi_response_size_bytes_count %d\n", cumulative)
+	b.WriteString("# HELP garyapi_image_bytes_served_total Image bytes served, by resource.\n")
+	b.WriteString("# TYPE garyapi_image_bytes_served_total counter\n")
+	fmt.Fprintf(&b, "garyapi_image_bytes_served_total{resource=\"gary\"} %d\n", garyResource.bytesServed.Load())
+	fmt.Fprintf(&b, "garyapi_image_bytes_served_total{resource=\"goober\"} %d\n", gooberResource.bytesServed.Load())
+	b.WriteString("# HELP garyapi_bytes_sent_total Response body bytes sent.\n")
+	b.WriteString("# TYPE garyapi_bytes_sent_total counter\n")
+	fmt.Fprintf(&b, "garyapi_bytes_sent_total %d\n", metrics.bytesSent.Load())
+	b.WriteString("# HELP garyapi_compression_bytes_total Bytes of compressed responses, before and after compression.\n")
+	b.WriteString("# TYPE garyapi_compression_bytes_total counter\n")
+	fmt.Fprintf(&b, "garyapi_compression_bytes_total{stage=\"uncompressed\"} %d\n", metrics.uncompressedBytes.Load())
+	fmt.Fprintf(&b, "garyapi_compression_bytes_total{stage=\"compressed\"} %d\n", metrics.compressedBytes.Load())
+	b.WriteString("# HELP garyapi_uptime_seconds Seconds since the server started.\n")
+	b.WriteString("# TYPE garyapi_uptime_seconds gauge\n")
+	fmt.Fprintf(&b, "garyapi_uptime_seconds %g\n", metrics.uptime().Seconds())
+
+	c.Data(http.StatusOK, "text/plain; version=0.0.4; charset=utf-8", []byte(b.String()))
+}
+
+func serveMetricsJSON(c *gin.Context) {
+	routes := make(map[string]uint64)
+	for _, rc := range metrics.routeCounts() {
+		routes[rc.Label] = rc.Count
+	}
+	statuses := make(map[string]uint64)
+	for _, sc := range metrics.statusCounts() {
+		statuses[sc.Label] = sc.Count
+	}
+	connections := make(map[string]uint64)
+	for i, label := range addressClassLabels {
+		connections[label] = metrics.addressClasses[i].Load()
+	}
+
+	c.JSON(http.StatusOK, gin.H{
+		"requests":           metrics.requestCount(),
+		"rps":                metrics.requestsPerSecond(),
+		"avg_response_us":    metrics.averageLatency().Microseconds(),
+		"p50_response_us":    metrics.responseTimeP50().Microseconds(),
+		"p95_response_us":    metrics.responseTimeP95().Microseconds(),
+		"p99_response_us":    metrics.responseTimeP99().Microseconds(),
+		"uptime_s":           metrics.uptime().Seconds(),
+		"active_connections": metrics.activeConnections.Load(),
+		"bytes_sent":         metrics.bytesSent.Load(),
+		"uncompressed_bytes": metrics.uncompressedBytes.Load(),
+		"compressed_bytes":   metrics.compressedBytes.Load(),
+		"compression_ratio":  metrics.compressionRatio(),
+		"timeouts":           metrics.timeouts.Load(),
+		"routes":             routes,
+		"statuses":           statuses,
+		"connections":        connections,
+	})
+}
+
+func serveHealthHandler(c *gin.Context) {
+	setCacheControl(c, "no-store")
+	c.Data(http.StatusOK, "application/json", healthBody)
+}
+
+func serveReadyHandler(c *gin.Context) {
+	setCacheControl(c, "no-store")
+	if !cacheReady.Load() {
+		c.Data(http.StatusServiceUnavailable, "application/json", notReadyBody)
+		return
+	}
+	c.Data(http.StatusOK, "application/json", healthBody)
+}
+
+func serveVersionHandler(c *gin.Context) {
+	c.JSON(http.StatusOK, gin.H{
+		"version": version,
+		"commit":  commit,
+		"built":   buildTime,
+	})
+}
+
+func loadDocs(path string) (*docsPage, error) {
+	info, err := os.Stat(path)
+	if err != nil {
+		return nil, fmt.Errorf("could not stat docs file %s: %w", path, err)
+	}
+	plain, err := os.ReadFile(path)
+	if err != nil {
+		return nil, fmt.Errorf("could not read docs file %s: %w", path, err)
+	}
+
+	var compressed bytes.Buffer
+	writer, err := gzip.NewWriterLevel(&compressed, gzip.BestCompression)
+	if err != nil {
+		return nil, err
+	}
+	if _, err := writer.Write(plain); err != nil {
+		return nil, fmt.Errorf("could not compress docs file %s: %w", path, err)
+	}
+	if err := writer.Close(); err != nil {
+		return nil, fmt.Errorf("could not compress docs file %s: %w", path, err)
+	}
+
+	return &docsPage{plain: plain, gzipped: compressed.Bytes(), modTime: info.ModTime()}, nil
+}
+
+func acceptsGzip(header string) bool {
+	for _, part := range strings.Split(header, ",") {
+		coding, params, _ := strings.Cut(part, ";")
+		if strings.TrimSpace(coding) != "gzip" {
+			continue
+		}
+		if q, ok := strings.CutPrefix(strings.TrimSpace(params), "q="); ok {
+			weight, err := strconv.ParseFloat(q, 64)
+			return err == nil && weight > 0
+		}
+		return true
+	}
+	return false
+}
+
+func serveDocsHandler(c *gin.Context) {
+	page := docs.Load()
+	if page == nil {
+		respondError(c, http.StatusNotFound, "not_found", "docs not available")
+		return
+	}
+
+	body := page.plain
+	if acceptsGzip(c.GetHeader("Accept-Encoding")) {
+		c.Header("Content-Encoding", "gzip")
+		body = page.gzipped
+		if c.Request.Method == http.MethodGet {
+			metrics.recordCompression(len(page.plain), len(page.gzipped))
+		}
+	}
+	setCacheControl(c, docsCacheControl)
+	c.Header("Vary", "Accept-Encoding")
+	setOptionalHeader(c, "Content-Security-Policy", currentConfig().DocsCSP)
+	http.ServeContent(c.Writer, c.Request, "index.html", page.modTime, bytes.NewReader(body))
+}
+
+// serveRootHandler answers / with the docs page, a redirect to a hosted
+// frontend, or a short API description, depending on ROOT_BEHAVIOR.
+func serveRootHandler(c *gin.Context) {
+	cfg := currentConfig()
+	switch cfg.RootBehavior {
+	case "redirect":
+		c.Redirect(http.StatusFound, cfg.RootRedirectURL)
+	case "json":
+		c.JSON(http.StatusOK, gin.H{
+			"name":    "Gary API",
+			"version": version,
+			"openapi": "/openapi.json",
+		})
+	default:
+		serveDocsHandler(c)
+	}
+}
+
+func reloadDocs(path string) {
+	if path == "" {
+		return
+	}
+	page, err := loadDocs(path)
 	if err != nil {
-		fmt.Printf("Failed to create watcher for %s: %v\n", label, err)
+		slog.Error("failed to load docs", "error", err)
 		return
 	}
-	err = watcher.Add(dir)
+	docs.Store(page)
+}
+
+// serveFaviconHandler serves the configured icon, or an empty 204 when
+// none is set so browsers stop asking without filling the logs with 404s.
+func serveFaviconHandler(icon []byte) gin.HandlerFunc {
+	return func(c *gin.Context) {
+		setCacheControl(c, faviconCacheControl)
+		if icon == nil {
+			c.Status(http.StatusNoContent)
+			return
+		}
+		c.Data(http.StatusOK, "image/x-icon", icon)
+	}
+}
+
+func serveOpenAPIHandler(c *gin.Context) {
+	c.Data(http.StatusOK, "application/json", openAPISpec)
+}
+
+func serveNotFoundHandler(body []byte, contentType string) gin.HandlerFunc {
+	return func(c *gin.Context) {
+		c.Data(http.StatusNotFound, contentType, body)
+	}
+}
+
+func watchDirs(watcher *fsnotify.Watcher, dirList string) {
+	for _, dir := range imageDirs(dirList) {
+		if err := watcher.Add(dir); err != nil {
+			slog.Error("failed to watch directory", "dir", dir, "error", err)
+		}
+	}
+}
+
+func startDirectoryWatcher(res *imageResource) {
+	label := res.label
+	watcher, err := fsnotify.NewWatcher()
 	if err != nil {
-		fmt.Printf("Failed to watch directory %s: %v\n", dir, err)
+		slog.Error("failed to create watcher", "resource", label, "error", err)
 		return
 	}
+	watchDirs(watcher, res.directory())
+
+	imageCacheMu.Lock()
+	res.watcher = watcher
+	imageCacheMu.Unlock()
 
 	go func() {
 		defer watcher.Close()
+		// Events are collected until the directories have been quiet for
+		// watchDebounce, so copying in a batch of files, or writing one in
+		// several chunks, costs a single rescan.
+		rescan := time.NewTimer(watchDebounce)
+		rescan.Stop()
+		defer rescan.Stop()
+		created := make(map[string]struct{})
 		for {
 			select {
 			case event, ok := <-watcher.Events:
 				if !ok {
 					return
 				}
-				if event.Op&(fsnotify.Create|fsnotify.Remove|fsnotify.Rename) != 0 {
-					imageCacheMu.Lock()
-					*cache = cacheFileNames(dir)
-					imageCacheMu.Unlock()
-					fmt.Printf("[%s] Cache updated due to event: %s\n", label, event)
+				if event.Op&(fsnotify.Create|fsnotify.Write|fsnotify.Remove|fsnotify.Rename) == 0 {
+					continue
+				}
+				if event.Has(fsnotify.Create) {
+					created[filepath.Base(event.Name)] = struct{}{}
 				}
+				rescan.Reset(watchDebounce)
+			case <-rescan.C:
+				res.refresh()
+				slog.Info("cache updated", "resource", label, "images", res.count())
+				for name := range created {
+					if res.has(name) {
+						res.publish(name)
+					}
+				}
+				clear(created)
 			case err, ok := <-watcher.Errors:
 				if !ok {
 					return
 				}
-				fmt.Printf("[%s] Watcher error: %v\n", label, err)
+				slog.Error("watcher error", "resource", label, "error", err)
 			}
 		}
 	}()
 }
 
-func main() {
-	_ = godotenv.Load()
-
-	runtime.GOMAXPROCS(runtime.NumCPU())
-	rand.Seed(time.Now().UnixNano())
-	gin.SetMode(gin.ReleaseMode)
-	r := gin.Default()
-
-	garyDir := os.Getenv("GARY_DIR")
-	gooberDir := os.Getenv("GOOBER_DIR")
-	quotesPath := os.Getenv("QUOTES_FILE")
-	jokesPath := os.Getenv("JOKES_FILE")
+// serveActiveHandler passes each request to the handler built from the
+// current configuration.
+func serveActiveHandler(w http.ResponseWriter, req *http.Request) {
+	(*activeHandler.Load()).ServeHTTP(w, req)
+}
 
-	garyImages = cacheFileNames(garyDir)
-	gooberImages = cacheFileNames(gooberDir)
+// newHandler builds the complete HTTP handler for cfg: routes, middleware
+// and the path-normalizing wrapper. It needs no listener, so the whole
+// stack can be driven directly with httptest.
+func newHandler(cfg Config) http.Handler {
+	workers := make(chan struct{}, max(cfg.ImageWorkers, 1))
+	imageWorkers.Store(&workers)
+	return withNormalizedPaths(newRouter(cfg))
+}
 
-	startDirectoryWatcher(garyDir, &garyImages, "Gary")
-	startDirectoryWatcher(gooberDir, &gooberImages, "Goober")
+// newRouter registers every route and middleware for cfg on a gin engine.
+func newRouter(cfg Config) *gin.Engine {
+	r := gin.New()
+	r.Use(assignRequestID, logRequest, gin.CustomRecovery(respondPanic))
+	r.Use(securityHeaders)
+	r.Use(metrics.middleware())
+	if cfg.MaxURILength > 0 {
+		r.Use(limitURILength(cfg.MaxURILength))
+	}
+	if cfg.RequestTimeout > 0 {
+		r.Use(requestTimeout(cfg.RequestTimeout))
+	}
+	r.Use(requireAPIKey)
+	r.Use(limitRequestBody)
+	r.Use(applyCacheControl)
 
-	r.Static("/Gary", garyDir)
-	r.Static("/Goober", gooberDir)
+	for _, method := range []string{http.MethodGet, http.MethodHead} {
+		r.Handle(method, "/Gary/*filepath", serveImageFileHandler(garyResource))
+		r.Handle(method, "/Goober/*filepath", serveImageFileHandler(gooberResource))
+	}
 
 	imageRoutes := r.Group("/")
 	{
-		imageRoutes.GET("/gary/image/*path", serveRandomImageHandler(&garyImages, defaultGaryImg, garyDir))
-		imageRoutes.GET("/goober/image/*path", serveRandomImageHandler(&gooberImages, defaultGooberImg, gooberDir))
+		// The bare paths are registered too so they don't bounce through a
+		// trailing-slash redirect to the catch-all.
+		imageRoutes.GET("/gary/image", serveRandomImageHandler(garyResource))
+		imageRoutes.GET("/gary/image/*path", serveRandomImageHandler(garyResource))
+		imageRoutes.GET("/goober/image", serveRandomImageHandler(gooberResource))
+		imageRoutes.GET("/goober/image/*path", serveRandomImageHandler(gooberResource))
+		imageRoutes.GET("/image/gary", serveRandomImageHandler(garyResource))
+		imageRoutes.GET("/image/gary/*path", serveRandomImageHandler(garyResource))
+		imageRoutes.GET("/image/goober", serveRandomImageHandler(gooberResource))
+		imageRoutes.GET("/image/goober/*path", serveRandomImageHandler(gooberResource))
+		for _, ext := range imageExtensions {
+			imageRoutes.GET("/gary/image."+ext, serveImageByExtensionHandler(garyResource, ext))
+			imageRoutes.GET("/goober/image."+ext, serveImageByExtensionHandler(gooberResource, ext))
+		}
 	}
 
 	apiRoutes := r.Group("/")
 	{
-		garyBaseURL := os.Getenv("GARYURL")
-		gooberBaseURL := os.Getenv("GOOBERURL")
-
-		apiRoutes.GET("/gary", serveImageURLHandler(garyBaseURL, garyDir, &garyImages, defaultGaryImg))
-		apiRoutes.GET("/goober", serveImageURLHandler(gooberBaseURL, gooberDir, &gooberImages, defaultGooberImg))
-		apiRoutes.GET("/quote", serveRandomLineHandler(quotesPath))
-		apiRoutes.GET("/joke", serveRandomLineHandler(jokesPath))
+		apiRoutes.GET("/gary", serveImageURLHandler(garyResource))
+		apiRoutes.GET("/goober", serveImageURLHandler(gooberResource))
+		apiRoutes.GET("/quote", serveRandomLineHandler("quote", quoteLines))
+		apiRoutes.GET("/joke", serveRandomLineHandler("joke", jokeLines))
+		apiRoutes.GET("/quote/:index", serveLineAtIndexHandler("quote", quoteLines))
+		apiRoutes.GET("/joke/:index", serveLineAtIndexHandler("joke", jokeLines))
+		apiRoutes.GET("/card", serveCardHandler)
+		apiRoutes.GET("/gary/list", serveImageListHandler(garyResource))
+		apiRoutes.GET("/goober/list", serveImageListHandler(gooberResource))
+		apiRoutes.GET("/gary/stream", serveImageStreamHandler(garyResource))
+		apiRoutes.GET("/goober/stream", serveImageStreamHandler(gooberResource))
+		apiRoutes.GET("/gary/bandwidth", func(c *gin.Context) {
+			respondJSON(c, http.StatusOK, gin.H{"bytes": garyResource.bytesServed.Load()})
+		})
+		apiRoutes.GET("/goober/bandwidth", func(c *gin.Context) {
+			respondJSON(c, http.StatusOK, gin.H{"bytes": gooberResource.bytesServed.Load()})
+		})
 
 		apiRoutes.GET("/gary/count", func(c *gin.Context) {
-			imageCacheMu.RLock()
-			count := len(garyImages)
-			imageCacheMu.RUnlock()
-			c.JSON(http.StatusOK, gin.H{"count": count})
+			respondJSONWithETag(c, garyResource.generation.Load(), gin.H{"count": garyResource.count()})
 		})
 		apiRoutes.GET("/goober/count", func(c *gin.Context) {
-			imageCacheMu.RLock()
-			count := len(gooberImages)
-			imageCacheMu.RUnlock()
-			c.JSON(http.StatusOK, gin.H{"count": count})
+			respondJSONWithETag(c, gooberResource.generation.Load(), gin.H{"count": gooberResource.count()})
+		})
+		apiRoutes.GET("/count", func(c *gin.Context) {
+			respondJSON(c, http.StatusOK, gin.H{
+				"gary":   garyResource.count(),
+				"goober": gooberResource.count(),
+				"quotes": quoteLines.count(),
+				"jokes":  jokeLines.count(),
+			})
 		})
 	}
 
-	indexFile := os.Getenv("INDEX_FILE")
-	if indexFile != "" {
-		r.GET("/", func(c *gin.Context) {
-			c.Header("Cache-Control", "no-store")
-			c.File(indexFile)
+	r.GET("/stats", serveStatsHandler)
+	r.GET("/metrics", serveMetricsHandler)
+	r.GET("/health", serveHealthHandler)
+	r.GET("/healthz", serveHealthHandler)
+	r.GET("/ready", serveReadyHandler)
+	r.GET("/version", serveVersionHandler)
+	r.GET("/openapi.json", serveOpenAPIHandler)
+	r.POST("/admin/reload", serveAdminReloadHandler)
+	r.GET("/debug/config", serveDebugConfigHandler)
+
+	var favicon []byte
+	if cfg.FaviconFile != "" {
+		icon, err := os.ReadFile(cfg.FaviconFile)
+		if err != nil {
+			slog.Error("failed to load favicon", "path", cfg.FaviconFile, "error", err)
+		} else {
+			favicon = icon
+		}
+	}
+	r.GET("/favicon.ico", serveFaviconHandler(favicon))
+	r.HEAD("/favicon.ico", serveFaviconHandler(favicon))
+
+	if cfg.IndexFile != "" || cfg.RootBehavior != "docs" {
+		reloadDocs(cfg.IndexFile)
+		r.GET("/", serveRootHandler)
+		r.HEAD("/", serveRootHandler)
+	}
+
+	if cfg.NotFoundBody != "" {
+		r.NoRoute(serveNotFoundHandler([]byte(cfg.NotFoundBody), cfg.NotFoundContentType))
+	} else {
+		r.NoRoute(func(c *gin.Context) {
+			respondError(c, http.StatusNotFound, "not_found", "route not found")
 		})
 	}
+	return r
+}
+
+// writeStartupReport writes a JSON summary of the running server to dest,
+// which is either "stderr" or a file path, so orchestration tooling can
+// confirm what started without parsing the logs.
+func writeStartupReport(dest string, cfg Config, address string, counts map[string]int) {
+	report, err := json.MarshalIndent(gin.H{
+		"address": address,
+		"version": version,
+		"counts":  counts,
+		"config":  redactConfig(cfg),
+	}, "", "  ")
+	if err != nil {
+		slog.Error("failed to build startup report", "error", err)
+		return
+	}
+	report = append(report, '\n')
+
+	if dest == "stderr" {
+		_, err = os.Stderr.Write(report)
+	} else {
+		err = os.WriteFile(dest, report, 0o644)
+	}
+	if err != nil {
+		slog.Error("failed to write startup report", "destination", dest, "error", err)
+	}
+}
+
+func main() {
+	loadDotenv()
+	registerImageMimeTypes()
+	cfg := configFromEnv()
+	initLogging(cfg)
+	if err := cfg.validate(); err != nil {
+		slog.Error("invalid configuration", "error", err)
+		os.Exit(1)
+	}
+	activeConfig.Store(&cfg)
+
+	runtime.GOMAXPROCS(cfg.WorkerThreads)
+	slog.Info("using worker threads", "count", cfg.WorkerThreads)
+	rand.Seed(time.Now().UnixNano())
+	gin.SetMode(gin.ReleaseMode)
+
+	loadResources(cfg)
+	counts := map[string]int{
+		"gary":   garyResource.count(),
+		"goober": gooberResource.count(),
+		"quotes": checkTextFile("QUOTES_FILE", quoteLines),
+		"jokes":  checkTextFile("JOKES_FILE", jokeLines),
+	}
+	slog.Info("content loaded",
+		"gary_images", counts["gary"],
+		"goober_images", counts["goober"],
+		"quotes", counts["quotes"],
+		"jokes", counts["jokes"])
+
+	startDirectoryWatcher(garyResource)
+	startDirectoryWatcher(gooberResource)
+	logMetricsPeriodically(cfg.MetricsLogInterval)
+	watchReloadSignal()
+
+	handler := newHandler(cfg)
+	activeHandler.Store(&handler)
+
+	server, address, served, err := startServer(cfg, http.HandlerFunc(serveActiveHandler))
+	if err != nil {
+		slog.Error("failed to start the server", "error", err)
+		return
+	}
+	server.RegisterOnShutdown(func() { close(streamsClosing) })
+
+	shutdownDone := make(chan struct{})
+	go func() {
+		defer close(shutdownDone)
+		stop := make(chan os.Signal, 1)
+		signal.Notify(stop, os.Interrupt, syscall.SIGTERM)
+		<-stop
+
+		ctx, cancel := context.WithTimeout(context.Background(), shutdownTimeout)
+		defer cancel()
+		if err := server.Shutdown(ctx); err != nil {
+			slog.Error("failed to shut down cleanly", "error", err)
+		}
+	}()
 
-	port := os.Getenv("PORT")
-	if port == "" {
-		port = "8080"
+	markReady()
+	slog.Info("listening", "address", address)
+	if cfg.StartupReport != "" {
+		writeStartupReport(cfg.StartupReport, cfg, address, counts)
 	}
-	if err := r.Run(":" + port); err != nil {
-		fmt.Printf("Failed to start the server: %v\n", err)
+	if err := <-served; err != nil {
+		if errors.Is(err, http.ErrServerClosed) {
+			<-shutdownDone
+			return
+		}
+		slog.Error("failed to start the server", "error", err)
 	}
 }
@@ -0,0 +1,1629 @@
+package main
+
+import (
+	"bytes"
+	"context"
+	"encoding/binary"
+	"encoding/json"
+	"errors"
+	"image"
+	"image/color"
+	"image/gif"
+	"image/png"
+	"io"
+	"io/fs"
+	"log/slog"
+	"maps"
+	"net"
+	"net/http"
+	"net/http/httptest"
+	"os"
+	"path/filepath"
+	"regexp"
+	"slices"
+	"strings"
+	"sync"
+	"sync/atomic"
+	"testing"
+	"time"
+
+	"github.com/gin-gonic/gin"
+)
+
+func TestMain(m *testing.M) {
+	gin.SetMode(gin.TestMode)
+	slog.SetDefault(slog.New(slog.NewTextHandler(io.Discard, nil)))
+	registerImageMimeTypes()
+	os.Exit(m.Run())
+}
+
+// writeTestFiles creates a temporary directory holding files and returns
+// its path.
+func writeTestFiles(t *testing.T, files map[string]string) string {
+	t.Helper()
+	dir := t.TempDir()
+	for name, content := range files {
+		if err := os.WriteFile(filepath.Join(dir, name), []byte(content), 0o644); err != nil {
+			t.Fatal(err)
+		}
+	}
+	return dir
+}
+
+// newTestServer points Gary at garyFiles, Goober at a single image and the
+// quotes and jokes at small JSON files, lets configure adjust the result,
+// and returns the handler main would serve for it.
+func newTestServer(t *testing.T, garyFiles map[string]string, configure func(*Config)) http.Handler {
+	t.Helper()
+	cfg := defaultConfig()
+	cfg.GaryDir = writeTestFiles(t, garyFiles)
+	cfg.GooberDir = writeTestFiles(t, map[string]string{"goober1.png": "goober"})
+	cfg.GaryURL = "https://example.com/Gary"
+	cfg.GooberURL = "https://example.com/Goober"
+	text := writeTestFiles(t, map[string]string{
+		"quotes.json": `["first quote", "second quote"]`,
+		"jokes.json":  `["first joke", "second joke"]`,
+	})
+	cfg.QuotesFile = filepath.Join(text, "quotes.json")
+	cfg.JokesFile = filepath.Join(text, "jokes.json")
+	if configure != nil {
+		configure(&cfg)
+	}
+
+	activeConfig.Store(&cfg)
+	loadResources(cfg)
+	return newHandler(cfg)
+}
+
+// serve sends a request through handler, with headers given as name/value
+// pairs.
+func serve(handler http.Handler, method, target string, headers ...string) *httptest.ResponseRecorder {
+	req := httptest.NewRequest(method, target, nil)
+	for i := 0; i+1 < len(headers); i += 2 {
+		req.Header.Set(headers[i], headers[i+1])
+	}
+	rec := httptest.NewRecorder()
+	handler.ServeHTTP(rec, req)
+	return rec
+}
+
+func decodeJSON(t *testing.T, rec *httptest.ResponseRecorder) map[string]any {
+	t.Helper()
+	var body map[string]any
+	if err := json.Unmarshal(rec.Body.Bytes(), &body); err != nil {
+		t.Fatalf("response is not JSON: %v: %q", err, rec.Body.String())
+	}
+	return body
+}
+
+var testGaryFiles = map[string]string{
+	"Gary1.png":  "gary one",
+	"Gary2.jpg":  "gary two",
+	"Gary3.gif":  "gary three",
+	"notes.webp": "gary four",
+}
+
+func TestRoutes(t *testing.T) {
+	handler := newTestServer(t, testGaryFiles, nil)
+
+	tests := []struct {
+		method string
+		path   string
+		status int
+	}{
+		{http.MethodGet, "/gary", http.StatusOK},
+		{http.MethodGet, "/goober", http.StatusOK},
+		{http.MethodGet, "/gary/count", http.StatusOK},
+		{http.MethodGet, "/goober/count", http.StatusOK},
+		{http.MethodGet, "/count", http.StatusOK},
+		{http.MethodGet, "/quote", http.StatusOK},
+		{http.MethodGet, "/joke", http.StatusOK},
+		{http.MethodGet, "/quote/1", http.StatusOK},
+		{http.MethodGet, "/joke/0", http.StatusOK},
+		{http.MethodGet, "/card", http.StatusOK},
+		{http.MethodGet, "/gary/list", http.StatusOK},
+		{http.MethodGet, "/goober/list", http.StatusOK},
+		{http.MethodGet, "/gary/bandwidth", http.StatusOK},
+		{http.MethodGet, "/goober/bandwidth", http.StatusOK},
+		{http.MethodGet, "/gary/image", http.StatusOK},
+		{http.MethodGet, "/gary/image/some-slug", http.StatusOK},
+		{http.MethodGet, "/goober/image/some-slug", http.StatusOK},
+		{http.MethodGet, "/image/gary", http.StatusOK},
+		{http.MethodGet, "/image/goober/goober1.png", http.StatusOK},
+		{http.MethodGet, "/gary/image.png", http.StatusOK},
+		{http.MethodGet, "/Gary/Gary1.png", http.StatusOK},
+		{http.MethodHead, "/Gary/Gary1.png", http.StatusOK},
+		{http.MethodGet, "/Goober/goober1.png", http.StatusOK},
+		{http.MethodGet, "/health", http.StatusOK},
+		{http.MethodGet, "/healthz", http.StatusOK},
+		{http.MethodGet, "/version", http.StatusOK},
+		{http.MethodGet, "/openapi.json", http.StatusOK},
+		{http.MethodGet, "/metrics", http.StatusOK},
+		{http.MethodGet, "/stats", http.StatusOK},
+		{http.MethodGet, "/favicon.ico", http.StatusNoContent},
+		{http.MethodPost, "/admin/reload", http.StatusForbidden},
+		{http.MethodGet, "/debug/config", http.StatusForbidden},
+		{http.MethodGet, "/no/such/route", http.StatusNotFound},
+	}
+	for _, tt := range tests {
+		t.Run(tt.method+" "+tt.path, func(t *testing.T) {
+			rec := serve(handler, tt.method, tt.path)
+			if rec.Code != tt.status {
+				t.Fatalf("status = %d, want %d; body %q", rec.Code, tt.status, rec.Body.String())
+			}
+		})
+	}
+}
+
+func TestAdminEndpointsAcceptToken(t *testing.T) {
+	handler := newTestServer(t, testGaryFiles, func(cfg *Config) {
+		cfg.AdminToken = "secret"
+		cfg.APIKeys = []string{"key"}
+	})
+
+	rec := serve(handler, http.MethodGet, "/debug/config", "Authorization", "Bearer secret", "X-API-Key", "key")
+	if rec.Code != http.StatusOK {
+		t.Fatalf("status = %d, want 200", rec.Code)
+	}
+	body := decodeJSON(t, rec)
+	if body["AdminToken"] != redacted {
+		t.Errorf("AdminToken = %v, want it redacted", body["AdminToken"])
+	}
+
+	for _, authorization := range []string{"Bearer wrong", "secret", "Basic secret"} {
+		rec = serve(handler, http.MethodPost, "/admin/reload", "Authorization", authorization, "X-API-Key", "key")
+		if rec.Code != http.StatusForbidden {
+			t.Errorf("status with Authorization %q = %d, want 403", authorization, rec.Code)
+		}
+	}
+}
+
+func TestAdminReloadPicksUpNewFiles(t *testing.T) {
+	handler := newTestServer(t, testGaryFiles, func(cfg *Config) {
+		cfg.AdminToken = "secret"
+	})
+
+	if err := os.WriteFile(filepath.Join(currentConfig().GaryDir, "Gary5.png"), []byte("gary five"), 0o644); err != nil {
+		t.Fatal(err)
+	}
+	rec := serve(handler, http.MethodPost, "/admin/reload", "Authorization", "Bearer secret")
+	if rec.Code != http.StatusOK {
+		t.Fatalf("status = %d, want 200", rec.Code)
+	}
+	if got := decodeJSON(t, rec)["gary"]; got != float64(len(testGaryFiles)+1) {
+		t.Errorf("gary count after reload = %v, want %d", got, len(testGaryFiles)+1)
+	}
+}
+
+func TestRequestTimeoutAnswersJSONAndIsCounted(t *testing.T) {
+	handler := newTestServer(t, testGaryFiles, func(cfg *Config) {
+		cfg.RequestTimeout = 50 * time.Millisecond
+		cfg.ImageWorkers = 1
+	})
+
+	// Hold the only image worker so the resize below can't start in time.
+	workers := *imageWorkers.Load()
+	workers <- struct{}{}
+	defer func() { <-workers }()
+
+	timeouts := metrics.timeouts.Load()
+	unavailable := metrics.statusCounts()[3].Count
+	rec := serve(handler, http.MethodGet, "/Gary/Gary1.png?w=10")
+	if rec.Code != http.StatusServiceUnavailable {
+		t.Fatalf("status = %d, want 503", rec.Code)
+	}
+	if code := decodeJSON(t, rec)["error"].(map[string]any)["code"]; code != "timeout" {
+		t.Errorf("error code = %v, want timeout", code)
+	}
+	if got := metrics.timeouts.Load(); got != timeouts+1 {
+		t.Errorf("timeouts = %d, want %d", got, timeouts+1)
+	}
+	if got := metrics.statusCounts()[3].Count; got != unavailable+1 {
+		t.Errorf("5xx responses = %d, want %d", got, unavailable+1)
+	}
+}
+
+// blockingStore is the filesystem store with opens that hang until release
+// is closed, like a read from a hung disk.
+type blockingStore struct {
+	fsImageStore
+	release chan struct{}
+}
+
+func (s blockingStore) open(location, name string) (imageFile, error) {
+	<-s.release
+	return s.fsImageStore.open(location, name)
+}
+
+// memStore is an imageStore held entirely in memory, keyed by location and
+// then by image name.
+type memStore map[string]map[string]string
+
+var memModTime = time.Date(2024, 1, 2, 3, 4, 5, 0, time.UTC)
+
+func (s memStore) scan(location string, previous imageScan) imageScan {
+	scan := imageScan{sizes: make(map[string]imageSize), files: make(map[string]imageFileInfo)}
+	for _, name := range slices.Sorted(maps.Keys(s[location])) {
+		scan.names = append(scan.names, name)
+		scan.files[name] = imageFileInfo{bytes: int64(len(s[location][name])), modTime: memModTime}
+		scan.latest = name
+	}
+	return scan
+}
+
+func (s memStore) open(location, name string) (imageFile, error) {
+	content, ok := s[location][name]
+	if !ok {
+		return nil, fs.ErrNotExist
+	}
+	return memFile{Reader: strings.NewReader(content), name: name}, nil
+}
+
+func (s memStore) available(location string) bool {
+	_, ok := s[location]
+	return ok
+}
+
+type memFile struct {
+	*strings.Reader
+	name string
+}
+
+func (f memFile) Close() error { return nil }
+
+func (f memFile) Stat() (os.FileInfo, error) { return memFileInfo(f), nil }
+
+type memFileInfo memFile
+
+func (i memFileInfo) Name() string       { return i.name }
+func (i memFileInfo) Size() int64        { return i.Reader.Size() }
+func (i memFileInfo) Mode() fs.FileMode  { return 0o444 }
+func (i memFileInfo) ModTime() time.Time { return memModTime }
+func (i memFileInfo) IsDir() bool        { return false }
+func (i memFileInfo) Sys() any           { return nil }
+
+func TestImageRoutesServeFromAnyStore(t *testing.T) {
+	store = memStore{"memory:gary": {"only.png": "gary from memory"}}
+	t.Cleanup(func() { store = fsImageStore{} })
+	handler := newTestServer(t, nil, func(cfg *Config) { cfg.GaryDir = "memory:gary" })
+
+	for _, path := range []string{"/gary/image", "/Gary/only.png"} {
+		if rec := serve(handler, http.MethodGet, path); rec.Code != http.StatusOK || rec.Body.String() != "gary from memory" {
+			t.Errorf("%s = %d %q, want the in-memory image", path, rec.Code, rec.Body.String())
+		}
+	}
+	if rec := serve(handler, http.MethodGet, "/Gary/missing.png"); rec.Code != http.StatusNotFound {
+		t.Errorf("missing image = %d, want 404", rec.Code)
+	}
+}
+
+func TestRequestTimeoutAnswersWhileHandlerIsBlocked(t *testing.T) {
+	handler := newTestServer(t, testGaryFiles, func(cfg *Config) {
+		cfg.RequestTimeout = 50 * time.Millisecond
+	})
+	release := make(chan struct{})
+	store = blockingStore{release: release}
+	server := httptest.NewServer(handler)
+	t.Cleanup(func() {
+		close(release)
+		server.Close()
+		store = fsImageStore{}
+	})
+
+	timeouts := metrics.timeouts.Load()
+	start := time.Now()
+	resp, err := http.Get(server.URL + "/Gary/Gary1.png")
+	if err != nil {
+		t.Fatal(err)
+	}
+	defer resp.Body.Close()
+	body, err := io.ReadAll(resp.Body)
+	if err != nil {
+		t.Fatal(err)
+	}
+	if elapsed := time.Since(start); elapsed > 2*time.Second {
+		t.Errorf("answered after %v, want soon after the 50ms deadline", elapsed)
+	}
+	if resp.StatusCode != http.StatusServiceUnavailable {
+		t.Fatalf("status = %d, want 503", resp.StatusCode)
+	}
+	var decoded struct {
+		Error struct {
+			Code string `json:"code"`
+		} `json:"error"`
+	}
+	if err := json.Unmarshal(body, &decoded); err != nil || decoded.Error.Code != "timeout" {
+		t.Errorf("body = %q, want a timeout error", body)
+	}
+	if got := metrics.timeouts.Load(); got != timeouts+1 {
+		t.Errorf("timeouts = %d, want %d", got, timeouts+1)
+	}
+}
+
+func TestRequestTimeoutDisabled(t *testing.T) {
+	handler := newTestServer(t, testGaryFiles, func(cfg *Config) {
+		cfg.RequestTimeout = 0
+	})
+	if rec := serve(handler, http.MethodGet, "/gary"); rec.Code != http.StatusOK {
+		t.Fatalf("status = %d, want 200", rec.Code)
+	}
+}
+
+func TestLargeImageRoundTrips(t *testing.T) {
+	data := make([]byte, 3<<20)
+	for i := range data {
+		data[i] = byte(i * 7)
+	}
+	handler := newTestServer(t, map[string]string{"big.png": string(data)}, nil)
+
+	rec := serve(handler, http.MethodGet, "/Gary/big.png")
+	if rec.Code != http.StatusOK {
+		t.Fatalf("status = %d, want 200", rec.Code)
+	}
+	if !bytes.Equal(rec.Body.Bytes(), data) {
+		t.Fatalf("body is %d bytes and differs from the %d-byte file", rec.Body.Len(), len(data))
+	}
+}
+
+// slowWriter is a client that takes a while over every chunk it reads.
+type slowWriter struct {
+	*httptest.ResponseRecorder
+}
+
+func (w slowWriter) Write(p []byte) (int, error) {
+	time.Sleep(2 * time.Millisecond)
+	return w.ResponseRecorder.Write(p)
+}
+
+func TestSlowDownloadOutlastsRequestTimeout(t *testing.T) {
+	data := make([]byte, 3<<20)
+	for i := range data {
+		data[i] = byte(i * 7)
+	}
+	handler := newTestServer(t, map[string]string{"big.png": string(data)}, func(cfg *Config) {
+		cfg.RequestTimeout = 50 * time.Millisecond
+	})
+
+	rec := slowWriter{httptest.NewRecorder()}
+	start := time.Now()
+	handler.ServeHTTP(rec, httptest.NewRequest(http.MethodGet, "/Gary/big.png", nil))
+	if elapsed := time.Since(start); elapsed < 50*time.Millisecond {
+		t.Fatalf("download took %v, too quick to outlast the timeout", elapsed)
+	}
+	if rec.Code != http.StatusOK {
+		t.Fatalf("status = %d, want 200", rec.Code)
+	}
+	if !bytes.Equal(rec.Body.Bytes(), data) {
+		t.Fatalf("body is %d bytes and differs from the %d-byte file", rec.Body.Len(), len(data))
+	}
+}
+
+func TestReloadConfigRebuildsHandler(t *testing.T) {
+	newTestServer(t, testGaryFiles, nil)
+	logger := slog.Default()
+	t.Cleanup(func() { slog.SetDefault(logger) })
+
+	t.Setenv("LOG_LEVEL", "error")
+	t.Setenv("GARY_DIR", currentConfig().GaryDir)
+	t.Setenv("NOT_FOUND_BODY", "gone")
+	reloadConfig()
+
+	rec := httptest.NewRecorder()
+	serveActiveHandler(rec, httptest.NewRequest(http.MethodGet, "/no/such/route", nil))
+	if rec.Code != http.StatusNotFound || rec.Body.String() != "gone" {
+		t.Fatalf("after reload got %d %q, want 404 \"gone\"", rec.Code, rec.Body.String())
+	}
+	if got := garyResource.count(); got != len(testGaryFiles) {
+		t.Errorf("gary count after reload = %d, want %d", got, len(testGaryFiles))
+	}
+}
+
+func TestRestartOnlyChanges(t *testing.T) {
+	tests := []struct {
+		name   string
+		change func(*Config)
+		want   []string
+	}{
+		{"nothing", func(*Config) {}, nil},
+		{"handler setting", func(cfg *Config) { cfg.RequestTimeout = time.Second }, nil},
+		{"listen address", func(cfg *Config) { cfg.ListenAddress = ":9090" }, []string{"LISTEN"}},
+		{"server timeouts", func(cfg *Config) {
+			cfg.HeaderReadTimeout = time.Second
+			cfg.IdleTimeout = time.Second
+		}, []string{"HEADER_READ_TIMEOUT", "IDLE_TIMEOUT"}},
+		{"connection limit", func(cfg *Config) { cfg.MaxConnections = 10 }, []string{"MAX_CONNECTIONS"}},
+	}
+	for _, tt := range tests {
+		t.Run(tt.name, func(t *testing.T) {
+			previous, next := defaultConfig(), defaultConfig()
+			tt.change(&next)
+			if got := restartOnlyChanges(&previous, &next); !slices.Equal(got, tt.want) {
+				t.Errorf("restartOnlyChanges = %v, want %v", got, tt.want)
+			}
+		})
+	}
+}
+
+func TestDotenvKeyRemovalRevertsToDefault(t *testing.T) {
+	if _, set := os.LookupEnv("GARY_DEFAULT_IMAGE"); set {
+		t.Skip("GARY_DEFAULT_IMAGE is set in the environment")
+	}
+	t.Chdir(t.TempDir())
+	t.Cleanup(func() {
+		os.Remove(".env")
+		loadDotenv()
+	})
+
+	if err := os.WriteFile(".env", []byte("GARY_DEFAULT_IMAGE=custom.jpg\n"), 0o644); err != nil {
+		t.Fatal(err)
+	}
+	loadDotenv()
+	if got := configFromEnv().GaryDefaultImage; got != "custom.jpg" {
+		t.Fatalf("GaryDefaultImage = %q, want custom.jpg", got)
+	}
+
+	if err := os.WriteFile(".env", nil, 0o644); err != nil {
+		t.Fatal(err)
+	}
+	loadDotenv()
+	if got := configFromEnv().GaryDefaultImage; got != defaultGaryImg {
+		t.Fatalf("GaryDefaultImage after removing the key = %q, want %q", got, defaultGaryImg)
+	}
+}
+
+// waitFor polls condition until it holds, failing the test after a few
+// seconds.
+func waitFor(t *testing.T, condition func() bool) {
+	t.Helper()
+	deadline := time.Now().Add(5 * time.Second)
+	for !condition() {
+		if time.Now().After(deadline) {
+			t.Fatal("condition not met within 5s")
+		}
+		time.Sleep(20 * time.Millisecond)
+	}
+}
+
+func TestWatcherPicksUpRewrittenFile(t *testing.T) {
+	newTestServer(t, testGaryFiles, nil)
+	startDirectoryWatcher(garyResource)
+	imageCacheMu.RLock()
+	watcher := garyResource.watcher
+	imageCacheMu.RUnlock()
+	if watcher == nil {
+		t.Skip("no filesystem watcher available")
+	}
+	t.Cleanup(func() {
+		imageCacheMu.Lock()
+		garyResource.watcher = nil
+		imageCacheMu.Unlock()
+		watcher.Close()
+	})
+
+	rewritten := "a longer gary one"
+	if err := os.WriteFile(filepath.Join(currentConfig().GaryDir, "Gary1.png"), []byte(rewritten), 0o644); err != nil {
+		t.Fatal(err)
+	}
+	waitFor(t, func() bool {
+		for _, listing := range garyResource.listImages() {
+			if listing.Name == "Gary1.png" {
+				return listing.Bytes == int64(len(rewritten))
+			}
+		}
+		return false
+	})
+}
+
+func TestImageListSorting(t *testing.T) {
+	handler := newTestServer(t, map[string]string{"b.png": "bb", "a.png": "aaa", "c.png": "c"}, nil)
+	base := time.Now().Add(-time.Hour)
+	for i, name := range []string{"c.png", "a.png", "b.png"} {
+		modTime := base.Add(time.Duration(i) * time.Minute)
+		if err := os.Chtimes(filepath.Join(currentConfig().GaryDir, name), modTime, modTime); err != nil {
+			t.Fatal(err)
+		}
+	}
+	loadResources(*currentConfig())
+
+	tests := []struct {
+		query string
+		want  []string
+	}{
+		{"", []string{"a.png", "b.png", "c.png"}},
+		{"?order=desc", []string{"c.png", "b.png", "a.png"}},
+		{"?sort=mtime", []string{"c.png", "a.png", "b.png"}},
+		{"?sort=mtime&order=desc", []string{"b.png", "a.png", "c.png"}},
+		{"?sort=size", []string{"c.png", "b.png", "a.png"}},
+	}
+	for _, tt := range tests {
+		t.Run(tt.query, func(t *testing.T) {
+			rec := serve(handler, http.MethodGet, "/gary/list"+tt.query)
+			var body struct {
+				Images []struct {
+					Name string `json:"name"`
+				} `json:"images"`
+			}
+			if err := json.Unmarshal(rec.Body.Bytes(), &body); err != nil {
+				t.Fatal(err)
+			}
+			var got []string
+			for _, listing := range body.Images {
+				got = append(got, listing.Name)
+			}
+			if !slices.Equal(got, tt.want) {
+				t.Errorf("order = %v, want %v", got, tt.want)
+			}
+		})
+	}
+}
+
+func TestScanReusesMeasuredSizes(t *testing.T) {
+	newTestServer(t, map[string]string{"gary.png": encodeTestPNG(t, 3, 2)}, nil)
+	dir := currentConfig().GaryDir
+
+	first := cacheFileNames(dir, imageScan{})
+	if got, want := first.sizes["gary.png"], (imageSize{Width: 3, Height: 2}); got != want {
+		t.Fatalf("size = %v, want %v", got, want)
+	}
+
+	// An unchanged file keeps whatever the previous scan measured.
+	stale := imageSize{Width: 99, Height: 99}
+	first.sizes["gary.png"] = stale
+	if got := cacheFileNames(dir, first).sizes["gary.png"]; got != stale {
+		t.Fatalf("size = %v, want reused %v", got, stale)
+	}
+
+	// A new modification time means the file is decoded again.
+	later := time.Now().Add(time.Minute)
+	if err := os.Chtimes(filepath.Join(dir, "gary.png"), later, later); err != nil {
+		t.Fatal(err)
+	}
+	if got, want := cacheFileNames(dir, first).sizes["gary.png"], (imageSize{Width: 3, Height: 2}); got != want {
+		t.Fatalf("size = %v, want %v", got, want)
+	}
+}
+
+func TestManifestSuppliesListsAndText(t *testing.T) {
+	manifest := httptest.NewServer(http.HandlerFunc(func(w http.ResponseWriter, r *http.Request) {
+		w.Write([]byte(`{"gary": ["remote1.png", "remote2.png", "remote3.png"], "goober": ["remote.png"], "quotes": ["manifest quote"]}`))
+	}))
+	defer manifest.Close()
+	handler := newTestServer(t, testGaryFiles, func(cfg *Config) {
+		cfg.ManifestURL = manifest.URL
+	})
+
+	// A rescan, as the watcher runs one, must not replace the manifest's list.
+	garyResource.refresh()
+
+	counts := decodeJSON(t, serve(handler, http.MethodGet, "/count"))
+	want := map[string]any{"gary": 3.0, "goober": 1.0, "quotes": 1.0, "jokes": 2.0}
+	for key, count := range want {
+		if counts[key] != count {
+			t.Errorf("%s = %v, want %v", key, counts[key], count)
+		}
+	}
+	if quote := decodeJSON(t, serve(handler, http.MethodGet, "/quote"))["quote"]; quote != "manifest quote" {
+		t.Errorf("quote = %v, want the manifest's", quote)
+	}
+}
+
+func TestManifestFailureFallsBackToDirectories(t *testing.T) {
+	manifest := httptest.NewServer(http.HandlerFunc(func(w http.ResponseWriter, r *http.Request) {
+		http.Error(w, "unavailable", http.StatusServiceUnavailable)
+	}))
+	defer manifest.Close()
+	handler := newTestServer(t, testGaryFiles, func(cfg *Config) {
+		cfg.ManifestURL = manifest.URL
+	})
+
+	counts := decodeJSON(t, serve(handler, http.MethodGet, "/count"))
+	want := map[string]any{"gary": float64(len(testGaryFiles)), "goober": 1.0, "quotes": 2.0, "jokes": 2.0}
+	for key, count := range want {
+		if counts[key] != count {
+			t.Errorf("%s = %v, want %v", key, counts[key], count)
+		}
+	}
+}
+
+// encodeTestPNG returns a blank width x height PNG.
+func encodeTestPNG(t *testing.T, width, height int) string {
+	t.Helper()
+	var encoded bytes.Buffer
+	if err := png.Encode(&encoded, image.NewRGBA(image.Rect(0, 0, width, height))); err != nil {
+		t.Fatal(err)
+	}
+	return encoded.String()
+}
+
+func TestResizeKeepsAspectRatioAndCaches(t *testing.T) {
+	handler := newTestServer(t, map[string]string{"wide.png": encodeTestPNG(t, 40, 20)}, func(cfg *Config) {
+		cfg.RequestTimeout = time.Second
+		cfg.ImageWorkers = 1
+	})
+
+	tests := []struct {
+		query         string
+		width, height int
+	}{
+		{"?w=10", 10, 5},
+		{"?h=4", 8, 4},
+		{"?w=6&h=6", 6, 6},
+	}
+	for _, tt := range tests {
+		t.Run(tt.query, func(t *testing.T) {
+			rec := serve(handler, http.MethodGet, "/Gary/wide.png"+tt.query)
+			if rec.Code != http.StatusOK {
+				t.Fatalf("status = %d, want 200; body %q", rec.Code, rec.Body.String())
+			}
+			if got := rec.Header().Get("Content-Type"); got != "image/png" {
+				t.Errorf("Content-Type = %q, want image/png", got)
+			}
+			config, err := png.DecodeConfig(rec.Body)
+			if err != nil {
+				t.Fatal(err)
+			}
+			if config.Width != tt.width || config.Height != tt.height {
+				t.Errorf("size = %dx%d, want %dx%d", config.Width, config.Height, tt.width, tt.height)
+			}
+		})
+	}
+
+	// With every worker busy only a cached result can be served in time.
+	workers := *imageWorkers.Load()
+	workers <- struct{}{}
+	defer func() { <-workers }()
+	if rec := serve(handler, http.MethodGet, "/Gary/wide.png?w=10"); rec.Code != http.StatusOK {
+		t.Fatalf("cached resize status = %d, want 200", rec.Code)
+	}
+}
+
+func TestResizeRefusesHugeImages(t *testing.T) {
+	var encoded bytes.Buffer
+	if err := gif.Encode(&encoded, image.NewPaletted(image.Rect(0, 0, 1, 1), color.Palette{color.Black}), nil); err != nil {
+		t.Fatal(err)
+	}
+	// Claim a 60000x60000 logical screen in the header.
+	data := encoded.Bytes()
+	binary.LittleEndian.PutUint16(data[6:], 60000)
+	binary.LittleEndian.PutUint16(data[8:], 60000)
+	handler := newTestServer(t, map[string]string{"bomb.gif": string(data)}, nil)
+
+	rec := serve(handler, http.MethodGet, "/Gary/bomb.gif?w=10")
+	if rec.Code != http.StatusRequestEntityTooLarge {
+		t.Fatalf("status = %d, want 413; body %q", rec.Code, rec.Body.String())
+	}
+}
+
+func TestResizedLRUEvictsByBytes(t *testing.T) {
+	lru := newResizedLRU(10)
+	lru.add("a", resizedImage{data: make([]byte, 4)})
+	lru.add("b", resizedImage{data: make([]byte, 4)})
+	lru.get("a")
+	lru.add("c", resizedImage{data: make([]byte, 4)})
+	lru.add("huge", resizedImage{data: make([]byte, 11)})
+
+	for key, want := range map[string]bool{"a": true, "b": false, "c": true, "huge": false} {
+		if _, ok := lru.get(key); ok != want {
+			t.Errorf("cached %q = %v, want %v", key, ok, want)
+		}
+	}
+	if lru.bytes != 8 {
+		t.Errorf("bytes = %d, want 8", lru.bytes)
+	}
+}
+
+func TestConcurrentResizesOfOneKeyDecodeOnce(t *testing.T) {
+	key := t.Name()
+	modTime := time.Now()
+	var decodes atomic.Int64
+	resize := func() (resizedImage, error) {
+		decodes.Add(1)
+		time.Sleep(50 * time.Millisecond)
+		return resizedImage{data: []byte("resized"), contentType: "image/png"}, nil
+	}
+
+	var wg sync.WaitGroup
+	for range 10 {
+		wg.Add(1)
+		go func() {
+			defer wg.Done()
+			resized, err := resizeOnce(context.Background(), key, modTime, resize)
+			if err != nil || string(resized.data) != "resized" {
+				t.Errorf("resizeOnce = %q, %v, want the shared result", resized.data, err)
+			}
+		}()
+	}
+	wg.Wait()
+	if got := decodes.Load(); got != 1 {
+		t.Errorf("resize ran %d times for one key, want 1", got)
+	}
+}
+
+func TestNotReadyWhileReloading(t *testing.T) {
+	var handler atomic.Pointer[http.Handler]
+	var readyDuringLoad atomic.Int64
+	manifest := httptest.NewServer(http.HandlerFunc(func(w http.ResponseWriter, r *http.Request) {
+		if h := handler.Load(); h != nil {
+			readyDuringLoad.Store(int64(serve(*h, http.MethodGet, "/ready").Code))
+		}
+		w.Write([]byte(`{"gary": ["remote.png"]}`))
+	}))
+	defer manifest.Close()
+	h := newTestServer(t, testGaryFiles, func(cfg *Config) {
+		cfg.ManifestURL = manifest.URL
+	})
+	handler.Store(&h)
+	t.Cleanup(func() { cacheReady.Store(false) })
+
+	if rec := serve(h, http.MethodGet, "/ready"); rec.Code != http.StatusServiceUnavailable {
+		t.Fatalf("status before listening = %d, want 503", rec.Code)
+	}
+	markReady()
+	reloadResources(*currentConfig())
+	if got := readyDuringLoad.Load(); got != http.StatusServiceUnavailable {
+		t.Errorf("status during reload = %d, want 503", got)
+	}
+	if rec := serve(h, http.MethodGet, "/ready"); rec.Code != http.StatusOK {
+		t.Errorf("status after reload = %d, want 200", rec.Code)
+	}
+}
+
+var pathParamPattern = regexp.MustCompile(`\{[^}/]+\}|[:*][^/]+`)
+
+// routeShape reduces a gin route or an OpenAPI path to a form where both
+// spell their parameters the same way.
+func routeShape(path string) string {
+	for _, ext := range imageExtensions {
+		if base, ok := strings.CutSuffix(path, "/image."+ext); ok {
+			path = base + "/image.{}"
+		}
+	}
+	return pathParamPattern.ReplaceAllString(path, "{}")
+}
+
+func TestOpenAPISpecCoversEveryRoute(t *testing.T) {
+	newTestServer(t, testGaryFiles, nil)
+	var spec struct {
+		Paths map[string]map[string]struct {
+			Parameters []struct {
+				Name   string `json:"name"`
+				Schema struct {
+					Enum []string `json:"enum"`
+				} `json:"schema"`
+			} `json:"parameters"`
+		} `json:"paths"`
+	}
+	if err := json.Unmarshal(openAPISpec, &spec); err != nil {
+		t.Fatal(err)
+	}
+	documented := make(map[string]string, len(spec.Paths))
+	for path := range spec.Paths {
+		documented[routeShape(path)] = path
+	}
+
+	cfg := *currentConfig()
+	cfg.RootBehavior = "json"
+	for _, route := range newRouter(cfg).Routes() {
+		path, ok := documented[routeShape(route.Path)]
+		if !ok {
+			t.Errorf("%s %s is not in the OpenAPI spec", route.Method, route.Path)
+			continue
+		}
+		if _, ok := spec.Paths[path][strings.ToLower(route.Method)]; !ok {
+			t.Errorf("%s %s is in the OpenAPI spec without its %s operation", route.Method, route.Path, route.Method)
+		}
+	}
+
+	for _, path := range []string{"/gary/image.{ext}", "/goober/image.{ext}"} {
+		if got := spec.Paths[path]["get"].Parameters[0].Schema.Enum; !slices.Equal(got, imageExtensions) {
+			t.Errorf("%s extensions = %v, want %v", path, got, imageExtensions)
+		}
+	}
+}
+
+func TestImageByExtensionServesEveryFormat(t *testing.T) {
+	files := make(map[string]string, len(imageExtensions))
+	for _, ext := range imageExtensions {
+		files["gary."+ext] = "gary as " + ext
+	}
+	handler := newTestServer(t, files, nil)
+
+	for _, ext := range imageExtensions {
+		t.Run(ext, func(t *testing.T) {
+			rec := serve(handler, http.MethodGet, "/gary/image."+ext)
+			if rec.Code != http.StatusOK {
+				t.Fatalf("status = %d, want 200", rec.Code)
+			}
+			if got, want := rec.Header().Get("Content-Type"), imageMimeTypes["."+ext]; got != want {
+				t.Errorf("Content-Type = %q, want %q", got, want)
+			}
+			if got, want := rec.Body.String(), "gary as "+ext; got != want {
+				t.Errorf("body = %q, want %q", got, want)
+			}
+		})
+	}
+}
+
+func TestImageURLCountHonoursExt(t *testing.T) {
+	handler := newTestServer(t, testGaryFiles, nil)
+
+	tests := []struct {
+		query  string
+		status int
+		urls   []string
+	}{
+		{"?count=5&ext=png", http.StatusOK, []string{"https://example.com/Gary/Gary1.png"}},
+		{"?count=5&ext=.gif", http.StatusOK, []string{"https://example.com/Gary/Gary3.gif"}},
+		{"?count=2&ext=tiff", http.StatusNotFound, nil},
+	}
+	for _, tt := range tests {
+		t.Run(tt.query, func(t *testing.T) {
+			rec := serve(handler, http.MethodGet, "/gary"+tt.query)
+			if rec.Code != tt.status {
+				t.Fatalf("status = %d, want %d", rec.Code, tt.status)
+			}
+			var body struct {
+				URLs []string `json:"urls"`
+			}
+			if err := json.Unmarshal(rec.Body.Bytes(), &body); err != nil {
+				t.Fatal(err)
+			}
+			if !slices.Equal(body.URLs, tt.urls) {
+				t.Errorf("urls = %v, want %v", body.URLs, tt.urls)
+			}
+		})
+	}
+}
+
+func TestListenReplacesOnlyStaleSockets(t *testing.T) {
+	socketPath := filepath.Join(t.TempDir(), "gary.sock")
+
+	stale, err := net.Listen("unix", socketPath)
+	if err != nil {
+		t.Skipf("unix sockets unavailable: %v", err)
+	}
+	stale.(*net.UnixListener).SetUnlinkOnClose(false)
+	stale.Close()
+
+	live, err := listen("unix:" + socketPath)
+	if err != nil {
+		t.Fatalf("listen over a stale socket: %v", err)
+	}
+	defer live.Close()
+
+	if second, err := listen("unix:" + socketPath); err == nil {
+		second.Close()
+		t.Fatal("listen took over a socket that is in use")
+	}
+	if conn, err := net.Dial("unix", socketPath); err != nil {
+		t.Errorf("socket in use was removed: %v", err)
+	} else {
+		conn.Close()
+	}
+}
+
+// acceptAll accepts from a connection-limited loopback listener until it is
+// closed, handing each connection to the returned channel.
+func acceptAll(t *testing.T, limit int, reject bool) (string, <-chan net.Conn) {
+	t.Helper()
+	inner, err := net.Listen("tcp", "127.0.0.1:0")
+	if err != nil {
+		t.Fatal(err)
+	}
+	ln := newLimitListener(inner, limit, reject)
+	accepted := make(chan net.Conn, 8)
+	go func() {
+		for {
+			conn, err := ln.Accept()
+			if err != nil {
+				return
+			}
+			accepted <- conn
+		}
+	}()
+	t.Cleanup(func() { ln.Close() })
+	return inner.Addr().String(), accepted
+}
+
+func dialTest(t *testing.T, address string) net.Conn {
+	t.Helper()
+	conn, err := net.Dial("tcp", address)
+	if err != nil {
+		t.Fatal(err)
+	}
+	t.Cleanup(func() { conn.Close() })
+	return conn
+}
+
+func TestLimitListenerWaitsForAFreeSlot(t *testing.T) {
+	address, accepted := acceptAll(t, 1, false)
+
+	dialTest(t, address)
+	first := <-accepted
+	dialTest(t, address)
+	select {
+	case conn := <-accepted:
+		conn.Close()
+		t.Fatal("a second connection was accepted while the only slot was taken")
+	case <-time.After(100 * time.Millisecond):
+	}
+
+	first.Close()
+	select {
+	case conn := <-accepted:
+		conn.Close()
+	case <-time.After(2 * time.Second):
+		t.Fatal("the waiting connection was not accepted once the slot freed up")
+	}
+}
+
+func TestLimitListenerRejectsOverTheLimit(t *testing.T) {
+	address, accepted := acceptAll(t, 1, true)
+
+	dialTest(t, address)
+	first := <-accepted
+	extra := dialTest(t, address)
+	_ = extra.SetReadDeadline(time.Now().Add(2 * time.Second))
+	if _, err := extra.Read(make([]byte, 1)); err == nil || errors.Is(err, os.ErrDeadlineExceeded) {
+		t.Errorf("connection over the limit read %v, want it closed by the server", err)
+	}
+	select {
+	case conn := <-accepted:
+		conn.Close()
+		t.Fatal("a connection over the limit was handed to the server")
+	default:
+	}
+
+	first.Close()
+	dialTest(t, address)
+	select {
+	case conn := <-accepted:
+		conn.Close()
+	case <-time.After(2 * time.Second):
+		t.Fatal("a new connection was refused after the slot freed up")
+	}
+}
+
+func TestOverlongURIsGet414ThroughTheMiddleware(t *testing.T) {
+	handler := newTestServer(t, testGaryFiles, nil)
+	clientErrors := metrics.statusClasses[2].Load()
+
+	for _, path := range []string{"/" + strings.Repeat("a", 5000), "/Gary/" + strings.Repeat("a", 5000) + ".png"} {
+		rec := serve(handler, http.MethodGet, path)
+		if rec.Code != http.StatusRequestURITooLong {
+			t.Fatalf("%d-char path = %d, want 414", len(path), rec.Code)
+		}
+		if code := decodeJSON(t, rec)["error"].(map[string]any)["code"]; code != "uri_too_long" {
+			t.Errorf("error code = %v, want uri_too_long", code)
+		}
+		if rec.Header().Get("X-Request-ID") == "" {
+			t.Error("414 carries no X-Request-ID")
+		}
+		if got := rec.Header().Get("X-Content-Type-Options"); got != "nosniff" {
+			t.Errorf("X-Content-Type-Options = %q, want nosniff", got)
+		}
+	}
+	if got := metrics.statusClasses[2].Load() - clientErrors; got != 2 {
+		t.Errorf("4xx count rose by %d, want 2", got)
+	}
+}
+
+func TestPanicsAnswerWithJSONError(t *testing.T) {
+	newTestServer(t, testGaryFiles, nil)
+	gin.DefaultErrorWriter = io.Discard
+	t.Cleanup(func() { gin.DefaultErrorWriter = os.Stderr })
+	router := newRouter(*currentConfig())
+	router.GET("/panic", func(*gin.Context) { panic("boom") })
+
+	rec := serve(router, http.MethodGet, "/panic")
+	if rec.Code != http.StatusInternalServerError {
+		t.Fatalf("status = %d, want 500", rec.Code)
+	}
+	if code := decodeJSON(t, rec)["error"].(map[string]any)["code"]; code != "internal_error" {
+		t.Errorf("error code = %v, want internal_error", code)
+	}
+	if rec.Header().Get("X-Request-ID") == "" {
+		t.Error("500 carries no X-Request-ID")
+	}
+}
+
+func TestRequestIDIsEchoedOnlyWhenSafe(t *testing.T) {
+	handler := newTestServer(t, testGaryFiles, nil)
+
+	tests := []struct {
+		name string
+		id   string
+		kept bool
+	}{
+		{"plain", "abc-123_DEF.4", true},
+		{"longest allowed", strings.Repeat("a", maxRequestIDLength), true},
+		{"too long", strings.Repeat("a", maxRequestIDLength+1), false},
+		{"empty", "", false},
+		{"space", "abc 123", false},
+		{"markup", "<script>", false},
+		{"non-ASCII", "grüße", false},
+	}
+	for _, tt := range tests {
+		t.Run(tt.name, func(t *testing.T) {
+			got := serve(handler, http.MethodGet, "/health", "X-Request-ID", tt.id).Header().Get("X-Request-ID")
+			if kept := got == tt.id; kept != tt.kept {
+				t.Errorf("X-Request-ID = %q for %q, want kept %v", got, tt.id, tt.kept)
+			}
+			if !requestIDPattern.MatchString(got) {
+				t.Errorf("X-Request-ID = %q is not a valid ID", got)
+			}
+		})
+	}
+}
+
+func TestImageContentTypes(t *testing.T) {
+	tests := []struct {
+		name        string
+		contentType string
+	}{
+		{"vector.svg", "image/svg+xml"},
+		{"vector.SVG", "image/svg+xml"},
+		{"photo.avif", "image/avif"},
+		{"photo.AVIF", "image/avif"},
+		{"old.bmp", "image/bmp"},
+		{"old.BMP", "image/bmp"},
+		{"scan.tif", "image/tiff"},
+		{"scan.TIFF", "image/tiff"},
+		{"Gary.JPG", "image/jpeg"},
+	}
+	files := make(map[string]string, len(tests))
+	for _, tt := range tests {
+		files[tt.name] = "content of " + tt.name
+	}
+	handler := newTestServer(t, files, nil)
+
+	for _, tt := range tests {
+		t.Run(tt.name, func(t *testing.T) {
+			rec := serve(handler, http.MethodGet, "/Gary/"+tt.name)
+			if rec.Code != http.StatusOK {
+				t.Fatalf("status = %d, want 200", rec.Code)
+			}
+			if got := rec.Header().Get("Content-Type"); got != tt.contentType {
+				t.Errorf("Content-Type = %q, want %q", got, tt.contentType)
+			}
+		})
+	}
+}
+
+func TestErrorsAreStructuredJSON(t *testing.T) {
+	handler := newTestServer(t, testGaryFiles, func(cfg *Config) {
+		cfg.JokesFile = filepath.Join(t.TempDir(), "missing.json")
+	})
+
+	tests := []struct {
+		method, path string
+		status       int
+		code         string
+	}{
+		{http.MethodGet, "/no/such/route", http.StatusNotFound, "not_found"},
+		{http.MethodGet, "/Gary/missing.png", http.StatusNotFound, "not_found"},
+		{http.MethodGet, "/quote/99", http.StatusNotFound, "not_found"},
+		{http.MethodGet, "/quote/abc", http.StatusBadRequest, "bad_request"},
+		{http.MethodGet, "/gary?count=abc", http.StatusBadRequest, "bad_request"},
+		{http.MethodGet, "/joke", http.StatusInternalServerError, "file_error"},
+		{http.MethodGet, "/Gary/notes.webp?w=10", http.StatusUnsupportedMediaType, "unsupported_media_type"},
+		{http.MethodPost, "/admin/reload", http.StatusForbidden, "forbidden"},
+	}
+	for _, tt := range tests {
+		t.Run(tt.method+" "+tt.path, func(t *testing.T) {
+			rec := serve(handler, tt.method, tt.path)
+			if rec.Code != tt.status {
+				t.Fatalf("status = %d, want %d", rec.Code, tt.status)
+			}
+			if got := rec.Header().Get("Content-Type"); !strings.HasPrefix(got, "application/json") {
+				t.Errorf("Content-Type = %q, want JSON", got)
+			}
+			var body struct {
+				Error struct {
+					Code    string `json:"code"`
+					Message string `json:"message"`
+				} `json:"error"`
+			}
+			if err := json.Unmarshal(rec.Body.Bytes(), &body); err != nil {
+				t.Fatalf("body %q: %v", rec.Body.String(), err)
+			}
+			if body.Error.Code != tt.code {
+				t.Errorf("code = %q, want %q", body.Error.Code, tt.code)
+			}
+			if body.Error.Message == "" {
+				t.Error("message is empty")
+			}
+		})
+	}
+}
+
+func TestImageSlugServesKnownFilename(t *testing.T) {
+	handler := newTestServer(t, testGaryFiles, nil)
+	contents := make(map[string]bool, len(testGaryFiles))
+	for _, content := range testGaryFiles {
+		contents[content] = true
+	}
+
+	tests := []struct {
+		path         string
+		body         string
+		cacheControl string
+	}{
+		{"/gary/image/Gary2.jpg", "gary two", "public, max-age=86400"},
+		{"/image/gary/Gary3.gif", "gary three", "public, max-age=86400"},
+		{"/gary/image/not-a-file", "", "no-store"},
+		{"/gary/image/gary2.jpg", "", "no-store"},
+	}
+	for _, tt := range tests {
+		t.Run(tt.path, func(t *testing.T) {
+			rec := serve(handler, http.MethodGet, tt.path)
+			if rec.Code != http.StatusOK {
+				t.Fatalf("status = %d, want 200", rec.Code)
+			}
+			if tt.body != "" && rec.Body.String() != tt.body {
+				t.Errorf("body = %q, want %q", rec.Body.String(), tt.body)
+			}
+			if tt.body == "" && !contents[rec.Body.String()] {
+				t.Errorf("body = %q, want one of the Gary images", rec.Body.String())
+			}
+			if got := rec.Header().Get("Cache-Control"); got != tt.cacheControl {
+				t.Errorf("Cache-Control = %q, want %q", got, tt.cacheControl)
+			}
+		})
+	}
+}
+
+func TestResourcePrefixesIgnoreCase(t *testing.T) {
+	tests := []struct {
+		path, normalized string
+	}{
+		{"/gary", "/gary"},
+		{"/Gary", "/gary"},
+		{"/GARY", "/gary"},
+		{"/GARY/COUNT", "/gary/count"},
+		{"/Goober/image/x", "/goober/image/x"},
+		{"/Gary/Image.PNG", "/gary/image.png"},
+		{"/IMAGE/Gary", "/image/gary"},
+		{"/Gary/Gary1.png", "/Gary/Gary1.png"},
+		{"/gary/Gary1.png", "/Gary/Gary1.png"},
+		{"/Quote", "/Quote"},
+	}
+	for _, tt := range tests {
+		if got := normalizeResourcePath(tt.path); got != tt.normalized {
+			t.Errorf("normalizeResourcePath(%q) = %q, want %q", tt.path, got, tt.normalized)
+		}
+	}
+
+	handler := newTestServer(t, testGaryFiles, nil)
+	for _, path := range []string{"/gary", "/Gary", "/GARY"} {
+		if url := decodeJSON(t, serve(handler, http.MethodGet, path))["url"]; url == nil {
+			t.Errorf("%s returned no image URL", path)
+		}
+	}
+	if rec := serve(handler, http.MethodGet, "/Goober/image/x"); rec.Code != http.StatusOK || rec.Body.String() != "goober" {
+		t.Errorf("/Goober/image/x = %d %q, want the Goober image", rec.Code, rec.Body.String())
+	}
+	if rec := serve(handler, http.MethodGet, "/gary/Gary1.png"); rec.Code != http.StatusOK || rec.Body.String() != "gary one" {
+		t.Errorf("/gary/Gary1.png = %d %q, want the file", rec.Code, rec.Body.String())
+	}
+}
+
+func TestEmptyAndMismatchedImagesAreRefused(t *testing.T) {
+	files := map[string]string{
+		"empty.png":   "",
+		"fake.png":    "this is not a png",
+		"real.png":    encodeTestPNG(t, 2, 2),
+		"unknown.bin": "anything",
+	}
+	for _, validate := range []bool{false, true} {
+		handler := newTestServer(t, files, func(cfg *Config) { cfg.ValidateImageContent = validate })
+
+		if rec := serve(handler, http.MethodGet, "/Gary/empty.png"); rec.Code != http.StatusNotFound {
+			t.Errorf("validate=%v: empty file = %d, want 404", validate, rec.Code)
+		}
+		if rec := serve(handler, http.MethodGet, "/Gary/real.png"); rec.Code != http.StatusOK || rec.Body.String() != files["real.png"] {
+			t.Errorf("validate=%v: real PNG = %d, want 200 with the file", validate, rec.Code)
+		}
+		if rec := serve(handler, http.MethodGet, "/Gary/unknown.bin"); rec.Code != http.StatusOK {
+			t.Errorf("validate=%v: unknown extension = %d, want 200", validate, rec.Code)
+		}
+		wantFake := http.StatusOK
+		if validate {
+			wantFake = http.StatusNotFound
+		}
+		if rec := serve(handler, http.MethodGet, "/Gary/fake.png"); rec.Code != wantFake {
+			t.Errorf("validate=%v: mismatched content = %d, want %d", validate, rec.Code, wantFake)
+		}
+	}
+}
+
+func TestLaterImageDirsOverrideOnlyWithRealFiles(t *testing.T) {
+	first := writeTestFiles(t, map[string]string{"first.png": "only first", "shared.png": "from first"})
+	second := writeTestFiles(t, map[string]string{"second.png": "only second", "shared.png": "from second"})
+	third := writeTestFiles(t, map[string]string{"shared.png": ""})
+	if err := os.Mkdir(filepath.Join(third, "second.png"), 0o755); err != nil {
+		t.Fatal(err)
+	}
+	dirs := strings.Join([]string{first, second, third}, string(os.PathListSeparator))
+	handler := newTestServer(t, nil, func(cfg *Config) { cfg.GaryDir = dirs })
+
+	for name, want := range map[string]string{
+		"first.png":  "only first",
+		"second.png": "only second",
+		"shared.png": "from second",
+	} {
+		if rec := serve(handler, http.MethodGet, "/Gary/"+name); rec.Code != http.StatusOK || rec.Body.String() != want {
+			t.Errorf("/Gary/%s = %d %q, want %q", name, rec.Code, rec.Body.String(), want)
+		}
+	}
+}
+
+func TestFileRouteKeepsFilesNamedLikeSubroutes(t *testing.T) {
+	files := maps.Clone(testGaryFiles)
+	files["image.png"] = "named image"
+	files["count"] = "named count"
+	handler := newTestServer(t, files, nil)
+
+	for path, want := range map[string]string{"/Gary/image.png": "named image", "/Gary/count": "named count"} {
+		if got := normalizeResourcePath(path); got != path {
+			t.Errorf("normalizeResourcePath(%q) = %q, want it unchanged", path, got)
+		}
+		if rec := serve(handler, http.MethodGet, path); rec.Code != http.StatusOK || rec.Body.String() != want {
+			t.Errorf("%s = %d %q, want the file %q", path, rec.Code, rec.Body.String(), want)
+		}
+	}
+	if got := normalizeResourcePath("/GARY/COUNT"); got != "/gary/count" {
+		t.Errorf("normalizeResourcePath(/GARY/COUNT) = %q, want the count route", got)
+	}
+	if got := decodeJSON(t, serve(handler, http.MethodGet, "/gary/count"))["count"]; got == nil {
+		t.Error("/gary/count no longer returns the count")
+	}
+}
+
+func TestReadLinesHandlesInvalidUTF8(t *testing.T) {
+	dir := writeTestFiles(t, map[string]string{
+		"bad.txt":   "good\nba\xffd\n",
+		"bad.json":  "[\"good\", \"ba\xffd\"]",
+		"bad.jsonl": "{\"text\": \"good\"}\n{\"text\": \"ba\xffd\"}\n",
+	})
+	previous := activeConfig.Load()
+	t.Cleanup(func() { activeConfig.Store(previous) })
+
+	for encoding, want := range map[string][]string{
+		"lossy":  {"good", "ba\uFFFDd"},
+		"strict": {"good"},
+	} {
+		cfg := defaultConfig()
+		cfg.TextEncoding = encoding
+		activeConfig.Store(&cfg)
+		for _, file := range []string{"bad.txt", "bad.json", "bad.jsonl"} {
+			got, err := readLinesFromFile(filepath.Join(dir, file))
+			if err != nil {
+				t.Fatalf("%s %s: %v", encoding, file, err)
+			}
+			if !slices.Equal(got, want) {
+				t.Errorf("%s %s: lines = %q, want %q", encoding, file, got, want)
+			}
+		}
+	}
+}
+
+func TestReadLinesFormats(t *testing.T) {
+	dir := writeTestFiles(t, map[string]string{
+		"quotes.json":  `["one", "two \"quoted\""]`,
+		"quotes.txt":   "one\n\n  two  \r\n   \nthree\n",
+		"quotes.jsonl": "{\"text\": \"one\"}\n\n{\"text\": \"two\", \"author\": \"gary\"}\n{\"other\": 1}\n",
+		"broken.jsonl": "{\"text\": \"one\"}\nnot json\n",
+		"broken.json":  `["unterminated`,
+	})
+
+	tests := []struct {
+		file    string
+		want    []string
+		wantErr bool
+	}{
+		{"quotes.json", []string{"one", `two "quoted"`}, false},
+		{"quotes.txt", []string{"one", "two", "three"}, false},
+		{"quotes.jsonl", []string{"one", "two"}, false},
+		{"broken.jsonl", nil, true},
+		{"broken.json", nil, true},
+		{"missing.txt", nil, true},
+	}
+	for _, tt := range tests {
+		t.Run(tt.file, func(t *testing.T) {
+			got, err := readLinesFromFile(filepath.Join(dir, tt.file))
+			if (err != nil) != tt.wantErr {
+				t.Fatalf("error = %v, want error %v", err, tt.wantErr)
+			}
+			if !slices.Equal(got, tt.want) {
+				t.Errorf("lines = %q, want %q", got, tt.want)
+			}
+		})
+	}
+}
+
+func TestAPIKeys(t *testing.T) {
+	handler := newTestServer(t, testGaryFiles, func(cfg *Config) {
+		cfg.APIKeys = []string{"first", "second"}
+	})
+
+	tests := []struct {
+		name    string
+		target  string
+		headers []string
+		status  int
+	}{
+		{"header", "/gary", []string{"X-API-Key", "second"}, http.StatusOK},
+		{"query", "/gary?api_key=first", nil, http.StatusOK},
+		{"invalid", "/gary", []string{"X-API-Key", "third"}, http.StatusUnauthorized},
+		{"prefix of a key", "/gary", []string{"X-API-Key", "firs"}, http.StatusUnauthorized},
+		{"missing", "/gary", nil, http.StatusUnauthorized},
+		{"health is open", "/health", nil, http.StatusOK},
+		{"healthz is open", "/healthz", nil, http.StatusOK},
+	}
+	for _, tt := range tests {
+		t.Run(tt.name, func(t *testing.T) {
+			rec := serve(handler, http.MethodGet, tt.target, tt.headers...)
+			if rec.Code != tt.status {
+				t.Fatalf("status = %d, want %d", rec.Code, tt.status)
+			}
+		})
+	}
+
+	open := newTestServer(t, testGaryFiles, nil)
+	if rec := serve(open, http.MethodGet, "/gary"); rec.Code != http.StatusOK {
+		t.Errorf("status without API_KEYS = %d, want 200", rec.Code)
+	}
+}
+
+func TestImageURLCount(t *testing.T) {
+	handler := newTestServer(t, testGaryFiles, nil)
+
+	tests := []struct {
+		query  string
+		status int
+		urls   int
+	}{
+		{"?count=1", http.StatusOK, 1},
+		{"?count=3", http.StatusOK, 3},
+		{"?count=100", http.StatusOK, len(testGaryFiles)},
+		{"?count=0", http.StatusBadRequest, 0},
+		{"?count=-2", http.StatusBadRequest, 0},
+	}
+	for _, tt := range tests {
+		t.Run(tt.query, func(t *testing.T) {
+			rec := serve(handler, http.MethodGet, "/gary"+tt.query)
+			if rec.Code != tt.status {
+				t.Fatalf("status = %d, want %d", rec.Code, tt.status)
+			}
+			var body struct {
+				URLs []string `json:"urls"`
+			}
+			if err := json.Unmarshal(rec.Body.Bytes(), &body); err != nil {
+				t.Fatal(err)
+			}
+			if len(body.URLs) != tt.urls {
+				t.Fatalf("got %d URLs, want %d", len(body.URLs), tt.urls)
+			}
+			seen := make(map[string]bool)
+			for _, url := range body.URLs {
+				if seen[url] {
+					t.Errorf("URL %s returned twice", url)
+				}
+				seen[url] = true
+			}
+		})
+	}
+
+	// Without count the response keeps the single-URL shape.
+	body := decodeJSON(t, serve(handler, http.MethodGet, "/gary"))
+	if _, ok := body["url"]; !ok {
+		t.Errorf("body = %v, want a url field", body)
+	}
+	if _, ok := body["urls"]; ok {
+		t.Errorf("body = %v, want no urls field", body)
+	}
+}
+
+func TestSwapNeverExposesAnEmptyList(t *testing.T) {
+	newTestServer(t, testGaryFiles, func(cfg *Config) {
+		cfg.GaryDefaultImage = "default.png"
+	})
+	cfg := *currentConfig()
+
+	done := make(chan struct{})
+	var readers sync.WaitGroup
+	var defaults atomic.Int64
+	for range 4 {
+		readers.Add(1)
+		go func() {
+			defer readers.Done()
+			for {
+				select {
+				case <-done:
+					return
+				default:
+				}
+				if garyResource.randomImage() == cfg.GaryDefaultImage || garyResource.count() == 0 {
+					defaults.Add(1)
+				}
+			}
+		}()
+	}
+	for range 50 {
+		loadResources(cfg)
+	}
+	close(done)
+	readers.Wait()
+
+	if n := defaults.Load(); n != 0 {
+		t.Errorf("readers saw an empty list %d times during reloads", n)
+	}
+}
+
+func TestShuffleBagShowsEveryImageOncePerCycle(t *testing.T) {
+	newTestServer(t, testGaryFiles, func(cfg *Config) {
+		cfg.ShuffleBag = true
+	})
+
+	want := slices.Sorted(maps.Keys(testGaryFiles))
+	checkCycle := func(cycle string) {
+		t.Helper()
+		var picks []string
+		for range len(want) {
+			picks = append(picks, garyResource.randomImage())
+		}
+		slices.Sort(picks)
+		if !slices.Equal(picks, want) {
+			t.Errorf("%s picked %v, want each of %v once", cycle, picks, want)
+		}
+	}
+	checkCycle("first cycle")
+	checkCycle("second cycle")
+
+	// A reload part way through a cycle starts a fresh bag.
+	garyResource.randomImage()
+	loadResources(*currentConfig())
+	checkCycle("cycle after reload")
+}
+
+// fixedSource always picks index, wrapped to the range asked for, and
+// "shuffles" into descending order.
+type fixedSource struct{ index int }
+
+func (s fixedSource) Intn(n int) int { return s.index % n }
+
+func (fixedSource) Perm(n int) []int {
+	perm := make([]int, n)
+	for i := range perm {
+		perm[i] = n - 1 - i
+	}
+	return perm
+}
+
+func TestRandomSelectionUsesRandomSource(t *testing.T) {
+	rng = fixedSource{index: 1}
+	t.Cleanup(func() { rng = mathRandSource{} })
+
+	images := []string{"a.png", "b.png", "c.png"}
+	if got := getRandomFileName(images, "default.png"); got != "b.png" {
+		t.Errorf("getRandomFileName = %q, want b.png", got)
+	}
+	if got := getRandomFileName(nil, "default.png"); got != "default.png" {
+		t.Errorf("getRandomFileName with no images = %q, want default.png", got)
+	}
+
+	res := &imageResource{images: images}
+	if got := res.randomImages(2, ""); !slices.Equal(got, []string{"c.png", "b.png"}) {
+		t.Errorf("randomImages(2) = %v, want [c.png b.png]", got)
+	}
+
+	// The bag pops from the end of the permutation, then reshuffles.
+	var picks []int
+	for range 4 {
+		picks = append(picks, res.nextFromBag())
+	}
+	if want := []int{0, 1, 2, 0}; !slices.Equal(picks, want) {
+		t.Errorf("nextFromBag picked %v, want %v", picks, want)
+	}
+}
+
+func TestStartServerReportsTheBoundAddress(t *testing.T) {
+	handler := newTestServer(t, testGaryFiles, func(cfg *Config) {
+		cfg.ListenAddress = "127.0.0.1:0"
+	})
+	server, address, served, err := startServer(*currentConfig(), handler)
+	if err != nil {
+		t.Fatal(err)
+	}
+	if strings.HasSuffix(address, ":0") {
+		t.Fatalf("startServer returned %q, want the port actually bound", address)
+	}
+
+	resp, err := http.Get("http://" + address + "/quote")
+	if err != nil {
+		t.Fatal(err)
+	}
+	var body map[string]any
+	err = json.NewDecoder(resp.Body).Decode(&body)
+	resp.Body.Close()
+	if err != nil || resp.StatusCode != http.StatusOK || body["quote"] == nil {
+		t.Errorf("/quote = %d %v (%v), want a quote", resp.StatusCode, body, err)
+	}
+
+	server.Close()
+	if err := <-served; !errors.Is(err, http.ErrServerClosed) {
+		t.Errorf("served = %v, want http.ErrServerClosed", err)
+	}
+}
+
+func TestJSONP(t *testing.T) {
+	handler := newTestServer(t, testGaryFiles, nil)
+
+	tests := []struct {
+		target      string
+		status      int
+		contentType string
+		prefix      string
+	}{
+		{"/gary/count?callback=handle", http.StatusOK, "application/javascript", "handle("},
+		{"/quote?callback=app.onQuote", http.StatusOK, "application/javascript", "app.onQuote("},
+		{"/count?callback=$_cb9", http.StatusOK, "application/javascript", "$_cb9("},
+		{"/gary/count", http.StatusOK, "application/json", "{"},
+		{"/gary/count?callback=alert(1)", http.StatusBadRequest, "application/json", "{"},
+		{"/joke?callback=a%3Bb", http.StatusBadRequest, "application/json", "{"},
+		{"/joke?callback=9lives", http.StatusBadRequest, "application/json", "{"},
+		{"/joke?callback=app..x", http.StatusBadRequest, "application/json", "{"},
+	}
+	for _, tt := range tests {
+		t.Run(tt.target, func(t *testing.T) {
+			rec := serve(handler, http.MethodGet, tt.target)
+			if rec.Code != tt.status {
+				t.Fatalf("status = %d, want %d", rec.Code, tt.status)
+			}
+			if got := rec.Header().Get("Content-Type"); !strings.HasPrefix(got, tt.contentType) {
+				t.Errorf("Content-Type = %q, want %s", got, tt.contentType)
+			}
+			if body := rec.Body.String(); !strings.HasPrefix(body, tt.prefix) {
+				t.Errorf("body = %q, want it to start with %q", body, tt.prefix)
+			}
+		})
+	}
+}
+
+func TestETagsFollowReloads(t *testing.T) {
+	handler := newTestServer(t, testGaryFiles, nil)
+
+	for _, target := range []string{"/gary/count", "/gary/list", "/gary/list?sort=size"} {
+		t.Run(target, func(t *testing.T) {
+			first := serve(handler, http.MethodGet, target)
+			etag := first.Header().Get("ETag")
+			if first.Code != http.StatusOK || etag == "" {
+				t.Fatalf("status = %d, ETag = %q; want 200 with an ETag", first.Code, etag)
+			}
+			for _, match := range []string{etag, "W/" + etag, `"other", ` + etag} {
+				if rec := serve(handler, http.MethodGet, target, "If-None-Match", match); rec.Code != http.StatusNotModified {
+					t.Errorf("If-None-Match %s: status = %d, want 304", match, rec.Code)
+				}
+			}
+
+			if err := os.WriteFile(filepath.Join(currentConfig().GaryDir, "Gary9.png"), []byte("new gary"), 0o644); err != nil {
+				t.Fatal(err)
+			}
+			loadResources(*currentConfig())
+			rec := serve(handler, http.MethodGet, target, "If-None-Match", etag)
+			if rec.Code != http.StatusOK {
+				t.Fatalf("status after reload = %d, want 200", rec.Code)
+			}
+			if next := rec.Header().Get("ETag"); next == etag || next == "" {
+				t.Errorf("ETag after reload = %q, want a new one", next)
+			}
+		})
+	}
+
+	count := serve(handler, http.MethodGet, "/gary/count").Header().Get("ETag")
+	list := serve(handler, http.MethodGet, "/gary/list").Header().Get("ETag")
+	if count == list {
+		t.Errorf("/gary/count and /gary/list share ETag %s", count)
+	}
+}